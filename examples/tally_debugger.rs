@@ -0,0 +1,573 @@
+//! A toy kernel for a tiny language, with a debugger that JupyterLab's
+//! visual debugger can drive: breakpoints, stepping and a variable view.
+//!
+//! Each line is either `name = expr` or `print expr`, where `expr` adds up
+//! integers and variables, e.g. `total = a + 2`. Lines starting with `#` and
+//! blank lines are skipped.
+//!
+//! Install it for the current user with
+//!
+//! ```text
+//! cargo run --example tally_debugger -- --install
+//! ```
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use juker::{
+    ConnectionInfo, JuDapResult, JuDebugger, JuExecContext, JuKernel, JuKernelInfo, JuKernelSpec, JuServerHandle,
+    message::{EvalResult, EvalValue},
+    server::JuServer,
+};
+use serde_json::{Value, json};
+use tokio::{select, sync::Notify};
+
+/// The seed JupyterLab hashes cell code with, reported in `debugInfo`.
+const HASH_SEED: u32 = 0xC70F6907;
+const SOURCE_SUFFIX: &str = ".tally";
+const THREAD_ID: u64 = 1;
+/// The `variablesReference` of the only scope.
+const GLOBALS_REF: u64 = 1;
+
+/// MurmurHash2, 32 bit, over the UTF-8 bytes of `code`: the `Murmur2` hash
+/// method JupyterLab derives cell source paths with.
+fn murmur2(code: &str, seed: u32) -> u32 {
+    const M: u32 = 0x5bd1e995;
+    let data = code.as_bytes();
+    let mut h = seed ^ data.len() as u32;
+
+    let mut chunks = data.chunks_exact(4);
+    for chunk in &mut chunks {
+        let mut k = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        k = k.wrapping_mul(M);
+        k ^= k >> 24;
+        k = k.wrapping_mul(M);
+        h = h.wrapping_mul(M) ^ k;
+    }
+
+    let tail = chunks.remainder();
+    if !tail.is_empty() {
+        for (i, b) in tail.iter().enumerate() {
+            h ^= (*b as u32) << (8 * i);
+        }
+        h = h.wrapping_mul(M);
+    }
+
+    h ^= h >> 13;
+    h = h.wrapping_mul(M);
+    h ^ (h >> 15)
+}
+
+fn source_dir() -> PathBuf {
+    std::env::temp_dir().join(format!("juker-tally-{}", std::process::id()))
+}
+
+fn source_prefix() -> String {
+    format!("{}{}", source_dir().display(), std::path::MAIN_SEPARATOR)
+}
+
+/// Where a cell's code is dumped, and what breakpoints refer to it by.
+fn source_path(code: &str) -> String {
+    format!("{}{}{}", source_prefix(), murmur2(code, HASH_SEED), SOURCE_SUFFIX)
+}
+
+/// Where a stopped execution is.
+struct Frame {
+    path: String,
+    line: u32,
+}
+
+/// State shared by the kernel, which runs the code, and the debugger, which
+/// answers the frontend from the control channel.
+#[derive(Default)]
+struct Shared {
+    vars: BTreeMap<String, i64>,
+    started: bool,
+    breakpoints: HashMap<String, Vec<u32>>,
+    stopped: Option<Frame>,
+    /// Stop at the next line whether or not it has a breakpoint.
+    stepping: bool,
+}
+
+#[derive(Default)]
+struct Tally {
+    shared: Arc<Mutex<Shared>>,
+    resume: Arc<Notify>,
+    server: Option<JuServerHandle>,
+}
+
+impl Tally {
+    fn shared(&self) -> std::sync::MutexGuard<'_, Shared> {
+        self.shared.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Why execution should stop before `line` of `path`, if it should.
+    fn stop_reason(&self, path: &str, line: u32) -> Option<&'static str> {
+        let mut shared = self.shared();
+        if !shared.started {
+            return None;
+        }
+        if std::mem::take(&mut shared.stepping) {
+            return Some("step");
+        }
+        let hit = shared.breakpoints.get(path).is_some_and(|lines| lines.contains(&line));
+        hit.then_some("breakpoint")
+    }
+
+    fn eval_expr(&self, expr: &str) -> Result<i64, String> {
+        let shared = self.shared();
+        expr.split('+')
+            .map(str::trim)
+            .map(|term| match term.parse::<i64>() {
+                Ok(n) => Ok(n),
+                Err(_) => shared.vars.get(term).copied().ok_or_else(|| format!("name {term:?} is not defined")),
+            })
+            .sum()
+    }
+
+    fn run_line(&self, line: &str, ctx: &JuExecContext) -> Result<(), String> {
+        if let Some(expr) = line.strip_prefix("print ") {
+            ctx.stdout(format!("{}\n", self.eval_expr(expr)?));
+            return Ok(());
+        }
+
+        let (name, expr) = line.split_once('=').ok_or_else(|| format!("cannot parse {line:?}"))?;
+        let value = self.eval_expr(expr)?;
+        self.shared().vars.insert(name.trim().to_string(), value);
+        Ok(())
+    }
+}
+
+impl JuKernel for Tally {
+    fn kernel_info(&self) -> JuKernelInfo {
+        JuKernelInfo {
+            name: "tally".to_string(),
+            version: "0.1.0".to_string(),
+            mimetype: "text/x-tally".to_string(),
+            file_extension: SOURCE_SUFFIX.to_string(),
+            banner: "Tally, a debuggable toy kernel".to_string(),
+            help_links: Vec::new(),
+        }
+    }
+
+    fn attach(&mut self, server: JuServerHandle) {
+        self.server = Some(server);
+    }
+
+    fn debugger(&mut self) -> Option<Box<dyn JuDebugger>> {
+        Some(Box::new(TallyDebugger {
+            shared: self.shared.clone(),
+            resume: self.resume.clone(),
+        }))
+    }
+
+    async fn eval_code(&mut self, code: String, ctx: JuExecContext) -> EvalResult {
+        let path = source_path(&code);
+
+        for (i, line) in code.lines().enumerate() {
+            let line_no = i as u32 + 1;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            if let Some(reason) = self.stop_reason(&path, line_no) {
+                self.shared().stopped = Some(Frame {
+                    path: path.clone(),
+                    line: line_no,
+                });
+                if let Some(server) = &self.server {
+                    server.debug_event(
+                        "stopped",
+                        json!({ "reason": reason, "threadId": THREAD_ID, "allThreadsStopped": true }),
+                    );
+                }
+
+                select! {
+                    _ = self.resume.notified() => {}
+                    _ = ctx.interrupted() => {
+                        self.shared().stopped = None;
+                        return EvalResult::interrupted();
+                    }
+                }
+            }
+
+            if let Err(evalue) = self.run_line(line, &ctx) {
+                return EvalResult::Error {
+                    ename: "TallyError".to_string(),
+                    traceback: vec![format!("line {line_no}: {evalue}")],
+                    evalue,
+                    payload: Vec::new(),
+                };
+            }
+        }
+
+        let summary = format!("{} variables", self.shared().vars.len());
+        EvalResult::Success {
+            results: vec![EvalValue {
+                data: json!({ "text/plain": summary }),
+                metadata: json!({}),
+                buffers: Vec::new(),
+            }],
+            payload: Vec::new(),
+        }
+    }
+
+    async fn restart(&mut self) {
+        let mut shared = self.shared();
+        shared.vars.clear();
+        shared.stopped = None;
+        shared.stepping = false;
+    }
+}
+
+struct TallyDebugger {
+    shared: Arc<Mutex<Shared>>,
+    resume: Arc<Notify>,
+}
+
+impl TallyDebugger {
+    fn shared(&self) -> std::sync::MutexGuard<'_, Shared> {
+        self.shared.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Lets a stopped execution go on, stopping again at the next line when
+    /// `step` is set.
+    fn go_on(&mut self, step: bool) -> JuDapResult {
+        let mut shared = self.shared();
+        if shared.stopped.take().is_none() {
+            return Err("Not stopped".to_string());
+        }
+        shared.stepping = step;
+        self.resume.notify_one();
+        Ok(json!({ "allThreadsContinued": true }))
+    }
+
+    fn variables_body(&self) -> Value {
+        let variables: Vec<Value> = self
+            .shared()
+            .vars
+            .iter()
+            .map(|(name, value)| {
+                json!({ "name": name, "value": value.to_string(), "type": "int", "variablesReference": 0 })
+            })
+            .collect();
+        json!({ "variables": variables })
+    }
+}
+
+impl JuDebugger for TallyDebugger {
+    fn initialize(&mut self, _args: Value) -> JuDapResult {
+        Ok(json!({ "supportsConfigurationDoneRequest": true }))
+    }
+
+    fn attach(&mut self, _args: Value) -> JuDapResult {
+        self.shared().started = true;
+        Ok(Value::Null)
+    }
+
+    fn disconnect(&mut self, _args: Value) -> JuDapResult {
+        let mut shared = self.shared();
+        shared.started = false;
+        shared.breakpoints.clear();
+        if shared.stopped.take().is_some() {
+            self.resume.notify_one();
+        }
+        Ok(Value::Null)
+    }
+
+    fn set_breakpoints(&mut self, args: Value) -> JuDapResult {
+        let path = args["source"]["path"].as_str().ok_or("setBreakpoints without a source path")?;
+        let lines: Vec<u32> = args["breakpoints"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|bp| bp["line"].as_u64())
+            .map(|line| line as u32)
+            .collect();
+
+        let breakpoints: Vec<Value> = lines
+            .iter()
+            .map(|line| json!({ "verified": true, "line": line, "source": { "path": path } }))
+            .collect();
+        self.shared().breakpoints.insert(path.to_string(), lines);
+        Ok(json!({ "breakpoints": breakpoints }))
+    }
+
+    fn resume(&mut self, _args: Value) -> JuDapResult {
+        self.go_on(false)
+    }
+
+    fn next(&mut self, _args: Value) -> JuDapResult {
+        self.go_on(true)
+    }
+
+    fn step_in(&mut self, _args: Value) -> JuDapResult {
+        self.go_on(true)
+    }
+
+    fn step_out(&mut self, _args: Value) -> JuDapResult {
+        self.go_on(false)
+    }
+
+    fn stack_trace(&mut self, _args: Value) -> JuDapResult {
+        let shared = self.shared();
+        let frames: Vec<Value> = shared
+            .stopped
+            .iter()
+            .map(|frame| {
+                json!({
+                    "id": 1,
+                    "name": "<cell>",
+                    "line": frame.line,
+                    "column": 1,
+                    "source": { "path": frame.path },
+                })
+            })
+            .collect();
+        Ok(json!({ "totalFrames": frames.len(), "stackFrames": frames }))
+    }
+
+    fn scopes(&mut self, _args: Value) -> JuDapResult {
+        Ok(json!({
+            "scopes": [{ "name": "Globals", "variablesReference": GLOBALS_REF, "expensive": false }]
+        }))
+    }
+
+    fn variables(&mut self, args: Value) -> JuDapResult {
+        match args["variablesReference"].as_u64() {
+            Some(GLOBALS_REF) => Ok(self.variables_body()),
+            _ => Ok(json!({ "variables": [] })),
+        }
+    }
+
+    fn inspect_variables(&mut self, _args: Value) -> JuDapResult {
+        Ok(self.variables_body())
+    }
+
+    fn dump_cell(&mut self, args: Value) -> JuDapResult {
+        let code = args["code"].as_str().ok_or("dumpCell without code")?;
+        let path = source_path(code);
+
+        std::fs::create_dir_all(source_dir()).map_err(|e| e.to_string())?;
+        std::fs::write(&path, code).map_err(|e| e.to_string())?;
+        Ok(json!({ "sourcePath": path }))
+    }
+
+    fn debug_info(&mut self, _args: Value) -> JuDapResult {
+        let shared = self.shared();
+        let breakpoints: Vec<Value> = shared
+            .breakpoints
+            .iter()
+            .map(|(path, lines)| {
+                let lines: Vec<Value> = lines.iter().map(|line| json!({ "line": line })).collect();
+                json!({ "source": path, "breakpoints": lines })
+            })
+            .collect();
+        let stopped: Vec<u64> = shared.stopped.iter().map(|_| THREAD_ID).collect();
+
+        Ok(json!({
+            "isStarted": shared.started,
+            "hashMethod": "Murmur2",
+            "hashSeed": HASH_SEED,
+            "tmpFilePrefix": source_prefix(),
+            "tmpFileSuffix": SOURCE_SUFFIX,
+            "breakpoints": breakpoints,
+            "stoppedThreads": stopped,
+            "richRendering": false,
+            "exceptionPaths": [],
+        }))
+    }
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let args: Vec<String> = std::env::args().collect();
+
+    if args.iter().any(|arg| arg == "--install") {
+        let spec = JuKernelSpec::for_current_exe("Tally", "tally", Vec::<String>::new())?;
+        let path = spec.install("tally")?;
+        eprintln!("Installed kernelspec tally in {}", path.display());
+        return Ok(());
+    }
+
+    let ci = match args.iter().position(|arg| arg == "--connection-file") {
+        Some(i) => ConnectionInfo::from_file(&args[i + 1])?,
+        None => {
+            let ci = ConnectionInfo::builder().kernel_name("tally").build()?;
+            let path = ci.write_to_runtime_dir()?;
+            eprintln!("To connect a client to this kernel, use:\n    --existing {}", path.display());
+            ci
+        }
+    };
+
+    JuServer::start(&ci, Tally::default()).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use bytes::Bytes;
+    use zeromq::{DealerSocket, Socket, SocketRecv, SocketSend, SubSocket, ZmqMessage};
+
+    use super::*;
+
+    #[test]
+    fn murmur2_matches_the_reference() {
+        assert_eq!(murmur2("", 0), 0);
+        assert_eq!(murmur2("", 1), 0x5bd15e36);
+        assert_eq!(murmur2("a", 0), 0x92685f5e);
+    }
+
+    /// A frontend speaking the unsigned wire protocol.
+    struct Client {
+        shell: DealerSocket,
+        control: DealerSocket,
+        iopub: SubSocket,
+    }
+
+    fn request(msg_type: &str, content: Value) -> ZmqMessage {
+        let header = json!({
+            "msg_id": uuid::Uuid::new_v4().to_string(),
+            "session": "client",
+            "msg_type": msg_type,
+            "version": "5.3",
+        });
+        let frames: Vec<Bytes> = [
+            Bytes::from_static(b"<IDS|MSG>"),
+            Bytes::new(),
+            header.to_string().into(),
+            "{}".into(),
+            "{}".into(),
+            content.to_string().into(),
+        ]
+        .into();
+        frames.try_into().unwrap()
+    }
+
+    /// The message type and content of a kernel message.
+    fn parse(zmsg: ZmqMessage) -> (String, Value) {
+        let frames = zmsg.into_vec();
+        let delim = frames.iter().position(|f| f.as_ref() == b"<IDS|MSG>").unwrap();
+        let header: Value = serde_json::from_slice(&frames[delim + 2]).unwrap();
+        let content = serde_json::from_slice(&frames[delim + 5]).unwrap();
+        (header["msg_type"].as_str().unwrap().to_string(), content)
+    }
+
+    impl Client {
+        async fn connect(ci: &ConnectionInfo) -> Self {
+            let endpoint = |port| format!("tcp://127.0.0.1:{port}");
+
+            let mut iopub = SubSocket::new();
+            iopub.subscribe("").await.unwrap();
+            iopub.connect(&endpoint(ci.iopub_port())).await.unwrap();
+            let mut shell = DealerSocket::new();
+            shell.connect(&endpoint(ci.shell_port())).await.unwrap();
+            let mut control = DealerSocket::new();
+            control.connect(&endpoint(ci.control_port())).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(100)).await;
+
+            Self { shell, control, iopub }
+        }
+
+        async fn shell(&mut self, msg_type: &str, content: Value) -> Value {
+            self.shell.send(request(msg_type, content)).await.unwrap();
+            parse(self.shell.recv().await.unwrap()).1
+        }
+
+        /// Sends a DAP request and returns the response's body.
+        async fn debug(&mut self, command: &str, arguments: Value) -> Value {
+            let dap = json!({ "seq": 1, "type": "request", "command": command, "arguments": arguments });
+            self.control.send(request("debug_request", dap)).await.unwrap();
+            let (msg_type, response) = parse(self.control.recv().await.unwrap());
+            assert_eq!(msg_type, "debug_reply");
+            assert_eq!(response["success"], true, "{command}: {response}");
+            response["body"].clone()
+        }
+
+        /// Waits for a `stopped` event and returns its reason.
+        async fn stopped(&mut self) -> String {
+            loop {
+                let (msg_type, content) = parse(self.iopub.recv().await.unwrap());
+                if msg_type == "debug_event" && content["event"] == "stopped" {
+                    return content["body"]["reason"].as_str().unwrap().to_string();
+                }
+            }
+        }
+
+        async fn stopped_line(&mut self) -> u64 {
+            let trace = self.debug("stackTrace", json!({ "threadId": THREAD_ID })).await;
+            trace["stackFrames"][0]["line"].as_u64().unwrap()
+        }
+
+        async fn variable_names(&mut self) -> Vec<String> {
+            let vars = self.debug("variables", json!({ "variablesReference": GLOBALS_REF })).await;
+            vars["variables"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|v| format!("{}={}", v["name"].as_str().unwrap(), v["value"].as_str().unwrap()))
+                .collect()
+        }
+    }
+
+    #[tokio::test]
+    async fn breakpoints_stop_execution_until_continued() {
+        let ci = ConnectionInfo::builder().signature_scheme("").build().unwrap();
+        let code = "a = 1\nb = a + 1\nprint a + b\n";
+
+        let client = async {
+            let mut client = Client::connect(&ci).await;
+
+            let info = client.shell("kernel_info_request", json!({})).await;
+            assert_eq!(info["debugger"], true);
+
+            client.debug("initialize", json!({ "adapterID": "tally" })).await;
+            client.debug("attach", json!({})).await;
+            let dumped = client.debug("dumpCell", json!({ "code": code })).await;
+            let path = dumped["sourcePath"].as_str().unwrap().to_string();
+            let set = client
+                .debug(
+                    "setBreakpoints",
+                    json!({ "source": { "path": path }, "breakpoints": [{ "line": 2 }] }),
+                )
+                .await;
+            assert_eq!(set["breakpoints"][0]["verified"], true);
+            client.debug("configurationDone", json!({})).await;
+
+            let execute = request("execute_request", json!({ "code": code, "silent": false }));
+            client.shell.send(execute).await.unwrap();
+
+            assert_eq!(client.stopped().await, "breakpoint");
+            assert_eq!(client.stopped_line().await, 2);
+            assert_eq!(client.variable_names().await, ["a=1"]);
+            let info = client.debug("debugInfo", json!({})).await;
+            assert_eq!(info["stoppedThreads"], json!([THREAD_ID]));
+
+            client.debug("next", json!({ "threadId": THREAD_ID })).await;
+            assert_eq!(client.stopped().await, "step");
+            assert_eq!(client.stopped_line().await, 3);
+            assert_eq!(client.variable_names().await, ["a=1", "b=2"]);
+
+            client.debug("continue", json!({ "threadId": THREAD_ID })).await;
+            let (msg_type, reply) = parse(client.shell.recv().await.unwrap());
+            assert_eq!(msg_type, "execute_reply");
+            assert_eq!(reply["status"], "ok");
+
+            client.control.send(request("shutdown_request", json!({ "restart": false }))).await.unwrap();
+            client.control.recv().await.unwrap();
+        };
+
+        let (res, ()) = tokio::time::timeout(Duration::from_secs(10), async {
+            tokio::join!(JuServer::start(&ci, Tally::default()), client)
+        })
+        .await
+        .unwrap();
+        res.unwrap();
+    }
+}
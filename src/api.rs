@@ -1,9 +1,131 @@
-use crate::message::EvalResult;
+use std::future::Future;
+
+use serde_json::Value;
+
+use crate::{context::JuExecContext, debugger::JuDebugger, handle::JuServerHandle, message::EvalResult};
 
 
 pub trait JuKernel {
     fn kernel_info(&self) -> JuKernelInfo;
-    fn eval_code(&mut self, code: String) -> impl std::future::Future<Output = EvalResult>;
+    /// Runs `code`. Output produced while it runs can be published through
+    /// `ctx`; the returned result becomes the cell's `execute_result`.
+    fn eval_code(&mut self, code: String, ctx: JuExecContext) -> impl Future<Output = EvalResult>;
+
+    /// Evaluates one of an execute request's `user_expressions`, after its
+    /// code ran successfully. The first result's data becomes the
+    /// expression's value. By default every expression fails.
+    fn user_expression(&mut self, expr: String) -> impl Future<Output = EvalResult> {
+        async move {
+            EvalResult::Error {
+                ename: "NotImplementedError".to_string(),
+                evalue: format!("cannot evaluate user expression {expr:?}"),
+                traceback: Vec::new(),
+                payload: Vec::new(),
+            }
+        }
+    }
+
+    /// Called once before the first request, with a handle the kernel may keep
+    /// to query the server later.
+    fn attach(&mut self, _server: JuServerHandle) {}
+
+    /// Called once, after [`JuKernel::attach`]. A kernel that returns a
+    /// debugger is advertised as debuggable and gets the frontend's
+    /// `debug_request`s routed to it. The default has none.
+    fn debugger(&mut self) -> Option<Box<dyn JuDebugger>> {
+        None
+    }
+
+    /// Called once the last execution is over. Without `restart` the sockets
+    /// close next; with it, [`JuKernel::restart`] follows.
+    fn shutdown(&mut self, _restart: bool) -> impl Future<Output = ()> {
+        async {}
+    }
+
+    /// Brings the kernel back to a fresh state when a client asks for a
+    /// restart. The server keeps its sockets and starts a new session; the
+    /// kernel keeps its [`JuServerHandle`] and comm targets. The default does
+    /// nothing.
+    fn restart(&mut self) -> impl Future<Output = ()> {
+        async {}
+    }
+
+    /// Tab completion at byte offset `cursor_pos` into `code`. The default
+    /// offers no matches.
+    fn complete(&mut self, _code: String, cursor_pos: usize) -> impl Future<Output = JuCompletion> {
+        async move { JuCompletion::empty(cursor_pos) }
+    }
+
+    /// Help for the object at byte offset `cursor_pos` into `code`, as shown by
+    /// Shift-Tab. `detail_level` is 0 for a summary and 1 for more detail.
+    /// `None` means nothing was found, which is also the default.
+    fn inspect(
+        &mut self,
+        _code: String,
+        _cursor_pos: usize,
+        _detail_level: u8,
+    ) -> impl Future<Output = Option<JuInspection>> {
+        async { None }
+    }
+
+    /// Whether `code` is ready to run, used by consoles to decide between
+    /// executing and continuing the input on a new line. The default answers
+    /// [`JuIsComplete::Unknown`]; [`JuBalanceChecker`] gives a reasonable
+    /// answer for bracket-structured languages.
+    ///
+    /// [`JuBalanceChecker`]: crate::JuBalanceChecker
+    fn is_complete(&mut self, _code: String) -> impl Future<Output = JuIsComplete> {
+        async { JuIsComplete::Unknown }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JuIsComplete {
+    Complete,
+    /// More input is needed; `indent` is the suggested indentation for the
+    /// next line.
+    Incomplete { indent: String },
+    Invalid,
+    Unknown,
+}
+
+/// Completion matches for the text between `cursor_start` and `cursor_end`,
+/// both byte offsets into the code.
+pub struct JuCompletion {
+    pub matches: Vec<String>,
+    pub cursor_start: usize,
+    pub cursor_end: usize,
+    /// Per-match details for frontends that show them, sent as
+    /// `_jupyter_types_experimental`.
+    pub items: Option<Vec<JuCompletionItem>>,
+}
+
+impl JuCompletion {
+    pub fn empty(cursor_pos: usize) -> Self {
+        Self {
+            matches: Vec::new(),
+            cursor_start: cursor_pos,
+            cursor_end: cursor_pos,
+            items: None,
+        }
+    }
+}
+
+/// One `_jupyter_types_experimental` entry; `start` and `end` are byte
+/// offsets into the code.
+pub struct JuCompletionItem {
+    pub start: usize,
+    pub end: usize,
+    pub text: String,
+    pub kind: Option<String>,
+    pub signature: Option<String>,
+}
+
+/// A MIME bundle answering an inspect request, e.g.
+/// `{"text/plain": "..."}`.
+pub struct JuInspection {
+    pub data: Value,
+    pub metadata: Value,
 }
 
 pub struct JuKernelInfo {
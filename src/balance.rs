@@ -0,0 +1,172 @@
+use crate::api::JuIsComplete;
+
+/// Answers `is_complete_request` by balancing brackets and quotes.
+///
+/// Code with unclosed brackets or an unterminated string is incomplete, and
+/// the suggested indent is one `indent_unit` per open bracket. A closing
+/// bracket that does not match the innermost open one makes the code invalid.
+///
+/// ```
+/// use juker::{JuBalanceChecker, JuIsComplete};
+///
+/// let checker = JuBalanceChecker::new().line_comment("//");
+/// assert_eq!(checker.check("f(1, 2)"), JuIsComplete::Complete);
+/// assert_eq!(
+///     checker.check("if x {"),
+///     JuIsComplete::Incomplete { indent: "    ".to_string() }
+/// );
+/// assert_eq!(checker.check("f(]"), JuIsComplete::Invalid);
+/// ```
+#[derive(Debug, Clone)]
+pub struct JuBalanceChecker {
+    brackets: Vec<(char, char)>,
+    quotes: Vec<char>,
+    escape: Option<char>,
+    line_comment: Option<String>,
+    indent_unit: String,
+}
+
+impl Default for JuBalanceChecker {
+    fn default() -> Self {
+        Self {
+            brackets: vec![('(', ')'), ('[', ']'), ('{', '}')],
+            quotes: vec!['"', '\''],
+            escape: Some('\\'),
+            line_comment: None,
+            indent_unit: "    ".to_string(),
+        }
+    }
+}
+
+impl JuBalanceChecker {
+    /// Balances `()`, `[]` and `{}`, with `"` and `'` strings using `\` as
+    /// the escape character.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn brackets(mut self, pairs: impl IntoIterator<Item = (char, char)>) -> Self {
+        self.brackets = pairs.into_iter().collect();
+        self
+    }
+
+    pub fn quotes(mut self, quotes: impl IntoIterator<Item = char>) -> Self {
+        self.quotes = quotes.into_iter().collect();
+        self
+    }
+
+    /// The character that escapes a quote inside a string, if any.
+    pub fn escape(mut self, escape: Option<char>) -> Self {
+        self.escape = escape;
+        self
+    }
+
+    /// Text that starts a comment running to the end of the line. Brackets
+    /// and quotes inside comments are ignored.
+    pub fn line_comment(mut self, prefix: impl Into<String>) -> Self {
+        self.line_comment = Some(prefix.into());
+        self
+    }
+
+    pub fn indent_unit(mut self, unit: impl Into<String>) -> Self {
+        self.indent_unit = unit.into();
+        self
+    }
+
+    pub fn check(&self, code: &str) -> JuIsComplete {
+        let mut open = Vec::new();
+        let mut quote = None;
+        let mut escaped = false;
+
+        let mut comment = false;
+
+        for (i, c) in code.char_indices() {
+            if comment {
+                comment = c != '\n';
+                continue;
+            }
+
+            if let Some(q) = quote {
+                if escaped {
+                    escaped = false;
+                } else if Some(c) == self.escape {
+                    escaped = true;
+                } else if c == q {
+                    quote = None;
+                }
+                continue;
+            }
+
+            if let Some(prefix) = &self.line_comment
+                && !prefix.is_empty()
+                && code[i..].starts_with(prefix.as_str())
+            {
+                comment = true;
+                continue;
+            }
+
+            if self.quotes.contains(&c) {
+                quote = Some(c);
+            } else if let Some(&(_, close)) = self.brackets.iter().find(|(o, _)| *o == c) {
+                open.push(close);
+            } else if self.brackets.iter().any(|(_, cl)| *cl == c) && open.pop() != Some(c) {
+                return JuIsComplete::Invalid;
+            }
+        }
+
+        if quote.is_some() || !open.is_empty() {
+            JuIsComplete::Incomplete {
+                indent: self.indent_unit.repeat(open.len()),
+            }
+        } else {
+            JuIsComplete::Complete
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn incomplete(indent: &str) -> JuIsComplete {
+        JuIsComplete::Incomplete {
+            indent: indent.to_string(),
+        }
+    }
+
+    #[test]
+    fn balanced_code_is_complete() {
+        let checker = JuBalanceChecker::new();
+        assert_eq!(checker.check(""), JuIsComplete::Complete);
+        assert_eq!(checker.check("a[0] = {x: (1, 2)}"), JuIsComplete::Complete);
+    }
+
+    #[test]
+    fn indent_follows_nesting_depth() {
+        let checker = JuBalanceChecker::new().indent_unit("  ");
+        assert_eq!(checker.check("f(["), incomplete("    "));
+        assert_eq!(checker.check("f([\n  1,\n]"), incomplete("  "));
+    }
+
+    #[test]
+    fn mismatched_closer_is_invalid() {
+        let checker = JuBalanceChecker::new();
+        assert_eq!(checker.check("f(1]"), JuIsComplete::Invalid);
+        assert_eq!(checker.check(")"), JuIsComplete::Invalid);
+    }
+
+    #[test]
+    fn brackets_in_strings_are_ignored() {
+        let checker = JuBalanceChecker::new();
+        assert_eq!(checker.check(r#"print("(", ')')"#), JuIsComplete::Complete);
+        assert_eq!(checker.check(r#"print("a\"b")"#), JuIsComplete::Complete);
+        assert_eq!(checker.check(r#"print("abc"#), incomplete("    "));
+    }
+
+    #[test]
+    fn brackets_in_comments_are_ignored() {
+        let checker = JuBalanceChecker::new().line_comment("#");
+        assert_eq!(checker.check("x = 1 # (\ny = 2"), JuIsComplete::Complete);
+        assert_eq!(checker.check("f( # )\n"), incomplete("    "));
+    }
+}
@@ -0,0 +1,451 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    sync::{
+        Arc, Mutex, MutexGuard,
+        atomic::{AtomicBool, Ordering},
+    },
+};
+
+use bytes::Bytes;
+use serde_json::{Value, json};
+use tracing::{debug, error, warn};
+use uuid::Uuid;
+
+use crate::{
+    iopub::IoPub,
+    protocol::{CommClose, CommInfo, CommMsg, CommOpen},
+};
+
+/// Accepts comms that frontends open to one target name.
+///
+/// Register with [`JuServerHandle::register_comm_target`].
+///
+/// [`JuServerHandle::register_comm_target`]: crate::JuServerHandle::register_comm_target
+pub trait JuCommTarget: Send {
+    /// A frontend opened `comm`. Returns the handler for its messages, or
+    /// `None` to refuse it, which closes the comm again.
+    fn open(&mut self, comm: &JuComm, data: Value, buffers: Vec<Bytes>) -> Option<Box<dyn JuCommHandler>>;
+}
+
+/// Receives the messages a frontend sends on one comm.
+pub trait JuCommHandler: Send {
+    fn on_msg(&mut self, comm: &JuComm, data: Value, buffers: Vec<Bytes>);
+
+    /// The frontend closed the comm. No more messages will arrive.
+    fn on_close(&mut self, _comm: &JuComm, _data: Value) {}
+}
+
+/// The kernel's end of an open comm. Cheap to clone.
+#[derive(Clone)]
+pub struct JuComm {
+    inner: Arc<CommInner>,
+}
+
+struct CommInner {
+    id: String,
+    target_name: String,
+    iopub: IoPub,
+    closed: AtomicBool,
+}
+
+impl JuComm {
+    fn new(id: String, target_name: String, iopub: IoPub) -> Self {
+        Self {
+            inner: Arc::new(CommInner {
+                id,
+                target_name,
+                iopub,
+                closed: AtomicBool::new(false),
+            }),
+        }
+    }
+
+    pub fn id(&self) -> &str {
+        &self.inner.id
+    }
+
+    pub fn target_name(&self) -> &str {
+        &self.inner.target_name
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.inner.closed.load(Ordering::SeqCst)
+    }
+
+    /// Sends a `comm_msg` to the frontend.
+    pub fn send(&self, data: impl Into<Value>, buffers: Vec<Bytes>) {
+        if self.is_closed() {
+            warn!("Dropping message for closed comm {}", self.id());
+            return;
+        }
+
+        let msg = CommMsg {
+            comm_id: self.id().to_string(),
+            data: data.into(),
+        };
+        self.inner.iopub.publish("comm_msg", msg, buffers);
+    }
+
+    /// Closes the comm from the kernel side. Closing twice does nothing.
+    pub fn close(&self, data: impl Into<Value>) {
+        if self.inner.closed.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        let msg = CommClose {
+            comm_id: self.id().to_string(),
+            data: data.into(),
+        };
+        self.inner.iopub.publish("comm_close", msg, Vec::new());
+    }
+}
+
+impl fmt::Debug for JuComm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("JuComm")
+            .field("id", &self.inner.id)
+            .field("target_name", &self.inner.target_name)
+            .field("closed", &self.is_closed())
+            .finish()
+    }
+}
+
+/// Comm targets and open comms, shared by the shell processor and the
+/// kernel's [`JuServerHandle`](crate::JuServerHandle).
+///
+/// Targets and handlers are taken out of the registry while they run, so
+/// they may open, close or register comms themselves.
+#[derive(Clone, Default)]
+pub(crate) struct CommManager {
+    state: Arc<Mutex<CommState>>,
+}
+
+#[derive(Default)]
+struct CommState {
+    /// `None` while the target is taken out to open a comm; unregistering it
+    /// meanwhile removes the entry, so it is not put back.
+    targets: HashMap<String, Option<Box<dyn JuCommTarget>>>,
+    comms: HashMap<String, OpenComm>,
+}
+
+struct OpenComm {
+    comm: JuComm,
+    handler: Option<Box<dyn JuCommHandler>>,
+}
+
+impl CommManager {
+    fn lock(&self) -> MutexGuard<'_, CommState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub(crate) fn register_target(&self, name: String, target: Box<dyn JuCommTarget>) {
+        self.lock().targets.insert(name, Some(target));
+    }
+
+    pub(crate) fn unregister_target(&self, name: &str) -> bool {
+        self.lock().targets.remove(name).is_some()
+    }
+
+    /// Opens a comm from the kernel side. `metadata` goes into the
+    /// `comm_open` message.
+    pub(crate) fn open(
+        &self,
+        iopub: &IoPub,
+        target_name: String,
+        data: Value,
+        metadata: Value,
+        buffers: Vec<Bytes>,
+        handler: Box<dyn JuCommHandler>,
+    ) -> JuComm {
+        let comm = JuComm::new(Uuid::new_v4().simple().to_string(), target_name, iopub.clone());
+        self.lock().comms.insert(
+            comm.id().to_string(),
+            OpenComm {
+                comm: comm.clone(),
+                handler: Some(handler),
+            },
+        );
+
+        let content = CommOpen {
+            comm_id: comm.id().to_string(),
+            target_name: comm.target_name().to_string(),
+            data,
+            target_module: None,
+        };
+        match iopub.child_message("comm_open").try_with_content(content) {
+            Ok(msg) => {
+                let mut msg = msg.with_buffers(buffers);
+                msg.metadata = metadata;
+                iopub.send(msg);
+            }
+            Err(e) => error!("Could not announce comm {}: {}", comm.id(), e),
+        }
+        comm
+    }
+
+    pub(crate) fn handle_open(&self, iopub: &IoPub, req: CommOpen, buffers: Vec<Bytes>) {
+        let comm = JuComm::new(req.comm_id, req.target_name, iopub.clone());
+
+        let taken = self.lock().targets.get_mut(comm.target_name()).map(Option::take);
+        let mut target = match taken {
+            Some(Some(target)) => target,
+            Some(None) => {
+                warn!("Comm target {:?} is busy, closing comm {}", comm.target_name(), comm.id());
+                comm.close(json!({}));
+                return;
+            }
+            None => {
+                warn!("No comm target {:?}, closing comm {}", comm.target_name(), comm.id());
+                comm.close(json!({}));
+                return;
+            }
+        };
+
+        let handler = target.open(&comm, req.data, buffers);
+
+        // Put the target back unless it was unregistered or replaced while
+        // it ran.
+        let mut state = self.lock();
+        if let Some(slot @ None) = state.targets.get_mut(comm.target_name()) {
+            *slot = Some(target);
+        }
+
+        match handler {
+            Some(handler) if !comm.is_closed() => {
+                state.comms.insert(
+                    comm.id().to_string(),
+                    OpenComm {
+                        comm,
+                        handler: Some(handler),
+                    },
+                );
+            }
+            _ => {
+                drop(state);
+                debug!("Comm target {:?} refused comm {}", comm.target_name(), comm.id());
+                comm.close(json!({}));
+            }
+        }
+    }
+
+    pub(crate) fn handle_msg(&self, req: CommMsg, buffers: Vec<Bytes>) {
+        let taken = self
+            .lock()
+            .comms
+            .get_mut(&req.comm_id)
+            .map(|open| (open.comm.clone(), open.handler.take()));
+
+        let (comm, mut handler) = match taken {
+            Some((comm, Some(handler))) => (comm, handler),
+            Some(_) => {
+                warn!("Comm {} is busy, dropping message", req.comm_id);
+                return;
+            }
+            None => {
+                warn!("Message for unknown comm {}", req.comm_id);
+                return;
+            }
+        };
+
+        handler.on_msg(&comm, req.data, buffers);
+
+        let mut state = self.lock();
+        if comm.is_closed() {
+            state.comms.remove(comm.id());
+        } else if let Some(open) = state.comms.get_mut(comm.id()) {
+            open.handler = Some(handler);
+        }
+    }
+
+    pub(crate) fn handle_close(&self, req: CommClose) {
+        let Some(open) = self.lock().comms.remove(&req.comm_id) else {
+            warn!("Close for unknown comm {}", req.comm_id);
+            return;
+        };
+
+        // Closed by the frontend, so no comm_close goes back.
+        open.comm.inner.closed.store(true, Ordering::SeqCst);
+        if let Some(mut handler) = open.handler {
+            handler.on_close(&open.comm, req.data);
+        }
+    }
+
+    /// Closes every open comm without telling the frontend, which drops its
+    /// own comms when the kernel restarts. Targets stay registered.
+    pub(crate) fn reset(&self) {
+        let comms = std::mem::take(&mut self.lock().comms);
+        for open in comms.into_values() {
+            open.comm.inner.closed.store(true, Ordering::SeqCst);
+        }
+    }
+
+    /// The open comms, optionally only those for `target_name`.
+    pub(crate) fn info(&self, target_name: Option<&str>) -> BTreeMap<String, CommInfo> {
+        let mut state = self.lock();
+        state.comms.retain(|_, open| !open.comm.is_closed());
+        state
+            .comms
+            .values()
+            .filter(|open| target_name.is_none_or(|name| open.comm.target_name() == name))
+            .map(|open| {
+                let info = CommInfo {
+                    target_name: open.comm.target_name().to_string(),
+                };
+                (open.comm.id().to_string(), info)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc::UnboundedReceiver;
+
+    use super::*;
+    use crate::JuMessage;
+
+    fn iopub() -> (IoPub, UnboundedReceiver<JuMessage>) {
+        IoPub::test_channel()
+    }
+
+    /// Echoes every message back, and closes the comm on `"bye"`.
+    struct Echo;
+
+    impl JuCommHandler for Echo {
+        fn on_msg(&mut self, comm: &JuComm, data: Value, buffers: Vec<Bytes>) {
+            if data == "bye" {
+                comm.close(json!({}));
+            } else {
+                comm.send(data, buffers);
+            }
+        }
+    }
+
+    struct EchoTarget;
+
+    impl JuCommTarget for EchoTarget {
+        fn open(&mut self, _comm: &JuComm, data: Value, _buffers: Vec<Bytes>) -> Option<Box<dyn JuCommHandler>> {
+            (data["accept"] == true).then(|| Box::new(Echo) as Box<dyn JuCommHandler>)
+        }
+    }
+
+    fn open_req(comm_id: &str, target_name: &str, accept: bool) -> CommOpen {
+        CommOpen {
+            comm_id: comm_id.to_string(),
+            target_name: target_name.to_string(),
+            data: json!({ "accept": accept }),
+            target_module: None,
+        }
+    }
+
+    fn msg_req(comm_id: &str, data: Value) -> CommMsg {
+        CommMsg {
+            comm_id: comm_id.to_string(),
+            data,
+        }
+    }
+
+    #[test]
+    fn frontend_opened_comm_round_trip() {
+        let (iopub, mut rx) = iopub();
+        let comms = CommManager::default();
+        comms.register_target("echo".into(), Box::new(EchoTarget));
+
+        comms.handle_open(&iopub, open_req("c1", "echo", true), Vec::new());
+        assert_eq!(comms.info(None).len(), 1);
+
+        comms.handle_msg(msg_req("c1", json!({"x": 1})), vec![Bytes::from_static(b"raw")]);
+        let sent = rx.try_recv().unwrap();
+        assert_eq!(sent.msg_type(), "comm_msg");
+        assert_eq!(sent.content, json!({"comm_id": "c1", "data": {"x": 1}}));
+        assert_eq!(sent.buffers, [Bytes::from_static(b"raw")]);
+
+        comms.handle_msg(msg_req("c1", json!("bye")), Vec::new());
+        assert_eq!(rx.try_recv().unwrap().msg_type(), "comm_close");
+        assert!(comms.info(None).is_empty());
+    }
+
+    /// Unregisters itself on its first open, which it accepts.
+    struct OneShotTarget(CommManager);
+
+    impl JuCommTarget for OneShotTarget {
+        fn open(&mut self, _comm: &JuComm, _data: Value, _buffers: Vec<Bytes>) -> Option<Box<dyn JuCommHandler>> {
+            assert!(self.0.unregister_target("once"));
+            Some(Box::new(Echo))
+        }
+    }
+
+    #[test]
+    fn target_unregistered_during_open_stays_unregistered() {
+        let (iopub, mut rx) = iopub();
+        let comms = CommManager::default();
+        comms.register_target("once".into(), Box::new(OneShotTarget(comms.clone())));
+
+        comms.handle_open(&iopub, open_req("c1", "once", true), Vec::new());
+        assert_eq!(comms.info(Some("once")).len(), 1);
+        assert!(rx.try_recv().is_err());
+
+        comms.handle_open(&iopub, open_req("c2", "once", true), Vec::new());
+        let sent = rx.try_recv().unwrap();
+        assert_eq!(sent.msg_type(), "comm_close");
+        assert_eq!(sent.content["comm_id"], "c2");
+        assert!(!comms.unregister_target("once"));
+    }
+
+    #[test]
+    fn reset_closes_comms_but_keeps_targets() {
+        let (iopub, mut rx) = iopub();
+        let comms = CommManager::default();
+        comms.register_target("echo".into(), Box::new(EchoTarget));
+        comms.handle_open(&iopub, open_req("c1", "echo", true), Vec::new());
+
+        comms.reset();
+        assert!(comms.info(None).is_empty());
+        assert!(rx.try_recv().is_err());
+
+        comms.handle_open(&iopub, open_req("c2", "echo", true), Vec::new());
+        assert_eq!(comms.info(None).len(), 1);
+    }
+
+    #[test]
+    fn unknown_target_and_refusal_close_the_comm() {
+        let (iopub, mut rx) = iopub();
+        let comms = CommManager::default();
+        comms.register_target("echo".into(), Box::new(EchoTarget));
+
+        comms.handle_open(&iopub, open_req("c1", "nope", true), Vec::new());
+        comms.handle_open(&iopub, open_req("c2", "echo", false), Vec::new());
+
+        for id in ["c1", "c2"] {
+            let sent = rx.try_recv().unwrap();
+            assert_eq!(sent.msg_type(), "comm_close");
+            assert_eq!(sent.content["comm_id"], id);
+        }
+        assert!(comms.info(None).is_empty());
+    }
+
+    #[test]
+    fn kernel_opened_comms_are_listed_by_target() {
+        let (iopub, mut rx) = iopub();
+        let comms = CommManager::default();
+
+        let a = comms.open(&iopub, "a".into(), json!({}), json!({}), Vec::new(), Box::new(Echo));
+        comms.open(&iopub, "b".into(), json!({}), json!({}), Vec::new(), Box::new(Echo));
+
+        let sent = rx.try_recv().unwrap();
+        assert_eq!(sent.msg_type(), "comm_open");
+        assert_eq!(sent.content["comm_id"], a.id());
+
+        let only_a = comms.info(Some("a"));
+        assert_eq!(only_a.keys().collect::<Vec<_>>(), [a.id()]);
+        assert_eq!(comms.info(None).len(), 2);
+
+        comms.handle_close(CommClose {
+            comm_id: a.id().to_string(),
+            data: json!({}),
+        });
+        assert!(a.is_closed());
+        assert!(comms.info(Some("a")).is_empty());
+    }
+}
@@ -1,4 +1,12 @@
+use std::{
+    net::TcpListener,
+    path::{Path, PathBuf},
+};
+
 use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{JuResult, secret::JuSecret};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ConnectionInfo {
@@ -9,7 +17,377 @@ pub struct ConnectionInfo {
     pub(crate) stdin_port: u16,
     pub(crate) hb_port: u16,
     pub(crate) iopub_port: u16,
-    pub(crate) key: String,
+    pub(crate) key: JuSecret,
     pub(crate) transport: String,
     pub(crate) signature_scheme: String,
-}
\ No newline at end of file
+}
+
+impl ConnectionInfo {
+    pub fn builder() -> ConnectionInfoBuilder {
+        ConnectionInfoBuilder::default()
+    }
+
+    /// Reads a connection file as written by Jupyter or by
+    /// [`ConnectionInfo::write_connection_file`].
+    pub fn from_file(path: impl AsRef<Path>) -> JuResult<Self> {
+        let f = std::fs::File::open(path)?;
+        Ok(serde_json::from_reader(f)?)
+    }
+
+    /// Replaces the key with the contents of `path`, ignoring a trailing
+    /// newline.
+    pub fn with_key_from_file(mut self, path: impl AsRef<Path>) -> JuResult<Self> {
+        let mut key = std::fs::read_to_string(path)?;
+        let len = key.trim_end_matches(['\r', '\n']).len();
+        key.truncate(len);
+
+        self.key = key.into();
+        Ok(self)
+    }
+
+    /// Replaces the key with the value of environment variable `var`, if set.
+    pub fn with_key_from_env(mut self, var: &str) -> Self {
+        if let Ok(key) = std::env::var(var) {
+            self.key = key.into();
+        }
+        self
+    }
+
+    /// Writes this connection info as JSON to `path`, readable only by the
+    /// current user where the platform supports it.
+    pub fn write_connection_file(&self, path: impl AsRef<Path>) -> JuResult<()> {
+        let path = path.as_ref();
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+
+        let f = options.open(path)?;
+        // `mode` only applies when the file is created; tighten an existing
+        // one before the key goes into it.
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            f.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+        }
+        serde_json::to_writer_pretty(f, self)?;
+        Ok(())
+    }
+
+    /// Writes a `kernel-<uuid>.json` connection file to the Jupyter runtime
+    /// directory, where `jupyter console --existing` looks for it.
+    pub fn write_to_runtime_dir(&self) -> JuResult<PathBuf> {
+        let path = jupyter_runtime_dir().join(format!("kernel-{}.json", Uuid::new_v4()));
+        self.write_connection_file(&path)?;
+        Ok(path)
+    }
+
+    pub fn kernel_name(&self) -> &str {
+        &self.kernel_name
+    }
+
+    pub fn ip(&self) -> &str {
+        &self.ip
+    }
+
+    pub fn transport(&self) -> &str {
+        &self.transport
+    }
+
+    pub fn signature_scheme(&self) -> &str {
+        &self.signature_scheme
+    }
+
+    pub fn key(&self) -> &JuSecret {
+        &self.key
+    }
+
+    pub fn shell_port(&self) -> u16 {
+        self.shell_port
+    }
+
+    pub fn iopub_port(&self) -> u16 {
+        self.iopub_port
+    }
+
+    pub fn stdin_port(&self) -> u16 {
+        self.stdin_port
+    }
+
+    pub fn control_port(&self) -> u16 {
+        self.control_port
+    }
+
+    pub fn hb_port(&self) -> u16 {
+        self.hb_port
+    }
+}
+
+/// The directory Jupyter keeps connection files in: `$JUPYTER_RUNTIME_DIR`,
+/// else `runtime` under the Jupyter data directory.
+pub fn jupyter_runtime_dir() -> PathBuf {
+    if let Some(dir) = std::env::var_os("JUPYTER_RUNTIME_DIR") {
+        return dir.into();
+    }
+    jupyter_data_dir().join("runtime")
+}
+
+pub(crate) fn jupyter_data_dir() -> PathBuf {
+    if let Some(dir) = std::env::var_os("JUPYTER_DATA_DIR") {
+        return dir.into();
+    }
+
+    let home = || PathBuf::from(std::env::var_os("HOME").unwrap_or_default());
+
+    if cfg!(target_os = "macos") {
+        home().join("Library").join("Jupyter")
+    } else if cfg!(windows) {
+        PathBuf::from(std::env::var_os("APPDATA").unwrap_or_default()).join("jupyter")
+    } else {
+        match std::env::var_os("XDG_DATA_HOME") {
+            Some(dir) if !dir.is_empty() => PathBuf::from(dir).join("jupyter"),
+            _ => home().join(".local").join("share").join("jupyter"),
+        }
+    }
+}
+
+/// Builds a [`ConnectionInfo`] for a kernel started outside of Jupyter.
+///
+/// Ports left at 0 are either probed for free ports at [`build`] time or,
+/// with `probe_ports(false)`, left at 0 for the server to pick when binding.
+///
+/// [`build`]: ConnectionInfoBuilder::build
+#[derive(Debug, Clone)]
+pub struct ConnectionInfoBuilder {
+    kernel_name: String,
+    ip: Option<String>,
+    transport: String,
+    signature_scheme: String,
+    key: Option<JuSecret>,
+    shell_port: u16,
+    iopub_port: u16,
+    stdin_port: u16,
+    control_port: u16,
+    hb_port: u16,
+    probe_ports: bool,
+}
+
+impl Default for ConnectionInfoBuilder {
+    fn default() -> Self {
+        Self {
+            kernel_name: String::new(),
+            ip: None,
+            transport: "tcp".to_string(),
+            signature_scheme: "hmac-sha256".to_string(),
+            key: None,
+            shell_port: 0,
+            iopub_port: 0,
+            stdin_port: 0,
+            control_port: 0,
+            hb_port: 0,
+            probe_ports: true,
+        }
+    }
+}
+
+impl ConnectionInfoBuilder {
+    pub fn kernel_name(mut self, name: impl Into<String>) -> Self {
+        self.kernel_name = name.into();
+        self
+    }
+
+    /// The address to bind, or for the `ipc` transport the socket path
+    /// prefix. Defaults to `127.0.0.1`, or a fresh prefix in the Jupyter
+    /// runtime directory for `ipc`.
+    pub fn ip(mut self, ip: impl Into<String>) -> Self {
+        self.ip = Some(ip.into());
+        self
+    }
+
+    pub fn transport(mut self, transport: impl Into<String>) -> Self {
+        self.transport = transport.into();
+        self
+    }
+
+    pub fn signature_scheme(mut self, scheme: impl Into<String>) -> Self {
+        self.signature_scheme = scheme.into();
+        self
+    }
+
+    /// Uses `key` instead of a freshly generated random one.
+    pub fn key(mut self, key: impl Into<JuSecret>) -> Self {
+        self.key = Some(key.into());
+        self
+    }
+
+    pub fn shell_port(mut self, port: u16) -> Self {
+        self.shell_port = port;
+        self
+    }
+
+    pub fn iopub_port(mut self, port: u16) -> Self {
+        self.iopub_port = port;
+        self
+    }
+
+    pub fn stdin_port(mut self, port: u16) -> Self {
+        self.stdin_port = port;
+        self
+    }
+
+    pub fn control_port(mut self, port: u16) -> Self {
+        self.control_port = port;
+        self
+    }
+
+    pub fn hb_port(mut self, port: u16) -> Self {
+        self.hb_port = port;
+        self
+    }
+
+    /// Whether [`build`](Self::build) replaces 0 ports with free ones. On by
+    /// default.
+    pub fn probe_ports(mut self, probe: bool) -> Self {
+        self.probe_ports = probe;
+        self
+    }
+
+    pub fn build(self) -> JuResult<ConnectionInfo> {
+        let ipc = self.transport == "ipc";
+        let ip = match self.ip {
+            Some(ip) => ip,
+            None if ipc => jupyter_runtime_dir()
+                .join(format!("kernel-{}-ipc", Uuid::new_v4()))
+                .to_string_lossy()
+                .into_owned(),
+            None => "127.0.0.1".to_string(),
+        };
+
+        let mut ports = [
+            self.shell_port,
+            self.iopub_port,
+            self.stdin_port,
+            self.control_port,
+            self.hb_port,
+        ];
+
+        if ipc && let Some(dir) = Path::new(&ip).parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+
+        if self.probe_ports && ipc {
+            // IPC "ports" are file name suffixes; take the lowest unused ones.
+            let mut next = 1u16;
+            for port in ports.iter_mut().filter(|p| **p == 0) {
+                while Path::new(&format!("{ip}-{next}")).exists() {
+                    next += 1;
+                }
+                *port = next;
+                next += 1;
+            }
+        } else if self.probe_ports {
+            // Keep every probe listener open until all ports are chosen so
+            // that no two sockets get the same port.
+            let mut listeners = Vec::new();
+            for port in ports.iter_mut().filter(|p| **p == 0) {
+                let listener = TcpListener::bind((ip.as_str(), 0))?;
+                *port = listener.local_addr()?.port();
+                listeners.push(listener);
+            }
+        }
+
+        let key = match self.key {
+            Some(key) => key,
+            None if self.signature_scheme.is_empty() => JuSecret::default(),
+            None => JuSecret::new(Uuid::new_v4().to_string()),
+        };
+
+        let [shell_port, iopub_port, stdin_port, control_port, hb_port] = ports;
+        Ok(ConnectionInfo {
+            kernel_name: self.kernel_name,
+            ip,
+            control_port,
+            shell_port,
+            stdin_port,
+            hb_port,
+            iopub_port,
+            key,
+            transport: self.transport,
+            signature_scheme: self.signature_scheme,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    #[test]
+    fn builder_probes_distinct_ports_and_generates_a_key() {
+        let ci = ConnectionInfo::builder().shell_port(0).build().unwrap();
+
+        let ports: HashSet<u16> = [
+            ci.shell_port,
+            ci.iopub_port,
+            ci.stdin_port,
+            ci.control_port,
+            ci.hb_port,
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(ports.len(), 5);
+        assert!(!ports.contains(&0));
+        assert!(!ci.key.is_empty());
+    }
+
+    #[test]
+    fn connection_file_round_trips() {
+        let ci = ConnectionInfo::builder()
+            .kernel_name("test")
+            .key("abc")
+            .probe_ports(false)
+            .hb_port(9000)
+            .build()
+            .unwrap();
+
+        let path = std::env::temp_dir().join(format!("juker-test-{}.json", Uuid::new_v4()));
+        ci.write_connection_file(&path).unwrap();
+        let read = ConnectionInfo::from_file(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(read.kernel_name, "test");
+        assert_eq!(read.key.expose(), "abc");
+        assert_eq!(read.hb_port, 9000);
+        assert_eq!(read.shell_port, 0);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn rewriting_a_connection_file_makes_it_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = std::env::temp_dir().join(format!("juker-test-{}.json", Uuid::new_v4()));
+        std::fs::write(&path, "{}").unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+
+        let ci = ConnectionInfo::builder()
+            .key("abc")
+            .probe_ports(false)
+            .build()
+            .unwrap();
+        ci.write_connection_file(&path).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(mode & 0o777, 0o600);
+    }
+}
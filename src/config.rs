@@ -0,0 +1,89 @@
+use std::{path::PathBuf, time::Duration};
+
+use crate::version::ProtocolVersion;
+
+/// Tunables for a [`JuServer`](crate::server::JuServer).
+#[derive(Debug, Clone)]
+pub struct JuServerConfig {
+    /// Messaging protocol version to speak. Decides the header version, the
+    /// kernel info fields and which optional message types are emitted.
+    pub protocol_version: ProtocolVersion,
+
+    /// How far a signed message's `header.date` may be from now before it
+    /// is refused as stale; signatures are remembered for as long as their
+    /// message could still be accepted. `Duration::ZERO` disables replay
+    /// protection.
+    pub replay_window: Duration,
+
+    /// Clock difference between client and kernel tolerated on top of
+    /// `replay_window`.
+    pub replay_clock_skew: Duration,
+
+    /// Maximum number of signatures remembered per session. When full, the
+    /// oldest entry is forgotten first, so size it above the number of
+    /// messages a session sends within the replay window.
+    pub replay_cache_size: usize,
+
+    /// Largest single frame accepted from a client, in bytes.
+    pub max_frame_size: usize,
+
+    /// Largest total message (all frames) accepted from a client, in bytes.
+    pub max_message_size: usize,
+
+    /// Where to write the connection file, with the ports actually bound,
+    /// once all sockets are up. Useful with ports left at 0.
+    pub connection_file: Option<PathBuf>,
+
+    /// How long an interrupted execution may take to stop by itself before
+    /// its future is dropped.
+    pub interrupt_timeout: Duration,
+
+    /// How long an execution still running when a shutdown is requested may
+    /// go on before it is interrupted. Any requests queued behind it are
+    /// dropped.
+    pub shutdown_timeout: Duration,
+
+    /// Treats SIGINT like an `interrupt_request`, for kernelspecs with
+    /// `interrupt_mode: "signal"`. Otherwise SIGINT keeps its default
+    /// behaviour of killing the process.
+    pub interrupt_on_sigint: bool,
+
+    /// Shuts down on SIGTERM as if a `shutdown_request` had arrived,
+    /// publishing the final status before exiting.
+    pub shutdown_on_sigterm: bool,
+
+    /// After a cell fails with `stop_on_error`, execute requests arriving
+    /// within this window, or already queued, are answered `aborted`
+    /// instead of run.
+    pub stop_on_error_timeout: Duration,
+
+    /// File that execution history is appended to, as JSON lines, and read
+    /// back from on the next start. History is kept in memory only when
+    /// unset.
+    pub history_file: Option<PathBuf>,
+
+    /// Masks code, outputs and input replies when message contents are
+    /// written to the log.
+    pub redact_message_logs: bool,
+}
+
+impl Default for JuServerConfig {
+    fn default() -> Self {
+        Self {
+            protocol_version: ProtocolVersion::default(),
+            replay_window: Duration::from_secs(300),
+            replay_clock_skew: Duration::from_secs(30),
+            replay_cache_size: 65536,
+            connection_file: None,
+            max_frame_size: 64 << 20,
+            max_message_size: 256 << 20,
+            interrupt_timeout: Duration::from_secs(3),
+            shutdown_timeout: Duration::from_secs(1),
+            interrupt_on_sigint: false,
+            shutdown_on_sigterm: false,
+            stop_on_error_timeout: Duration::from_millis(100),
+            history_file: None,
+            redact_message_logs: false,
+        }
+    }
+}
@@ -0,0 +1,270 @@
+use serde_json::{Value, json};
+use tokio_util::sync::CancellationToken;
+use tracing::{error, warn};
+
+use crate::{
+    JuError, JuResult,
+    iopub::IoPub,
+    message::EvalValue,
+    protocol::{ClearOutput, DisplayData, Header, Stream, StreamName, UpdateDisplayData},
+    stdin::Stdin,
+};
+
+/// The execute request a [`JuKernel::eval_code`] call is running, and the
+/// way to publish output for it while it runs.
+///
+/// Everything published here is attributed to the request, so frontends show
+/// it in the right cell. Cheap to clone, and may be moved into tasks that
+/// outlive the call, although output published after the reply may be
+/// ignored by frontends.
+///
+/// [`JuKernel::eval_code`]: crate::JuKernel::eval_code
+#[derive(Clone)]
+pub struct JuExecContext {
+    iopub: IoPub,
+    stdin: Option<Stdin>,
+    parent: Header,
+    execution_count: u32,
+    metadata: Value,
+    cancel: CancellationToken,
+}
+
+impl JuExecContext {
+    pub(crate) fn new(
+        iopub: IoPub,
+        stdin: Option<Stdin>,
+        parent: Header,
+        execution_count: u32,
+        metadata: Value,
+        cancel: CancellationToken,
+    ) -> Self {
+        Self {
+            iopub,
+            stdin,
+            parent,
+            execution_count,
+            metadata,
+            cancel,
+        }
+    }
+
+    pub fn execution_count(&self) -> u32 {
+        self.execution_count
+    }
+
+    /// The metadata of the execute request.
+    pub fn metadata(&self) -> &Value {
+        &self.metadata
+    }
+
+    /// The id of the notebook cell being run, if the frontend sent one.
+    pub fn cell_id(&self) -> Option<&str> {
+        self.metadata["cellId"].as_str()
+    }
+
+    /// The header of the execute request.
+    pub fn request_header(&self) -> &Header {
+        &self.parent
+    }
+
+    /// Whether the execution was interrupted. Long-running code should check
+    /// this, or await [`JuExecContext::interrupted`], and return soon after;
+    /// otherwise the server drops the evaluation after
+    /// [`interrupt_timeout`](crate::JuServerConfig::interrupt_timeout).
+    pub fn is_interrupted(&self) -> bool {
+        self.cancel.is_cancelled()
+    }
+
+    /// Completes when the execution is interrupted.
+    pub async fn interrupted(&self) {
+        self.cancel.cancelled().await
+    }
+
+    /// The token cancelled on interrupt, for handing to other tasks.
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.cancel.clone()
+    }
+
+    pub fn stdout(&self, text: impl Into<String>) {
+        self.stream(StreamName::Stdout, text);
+    }
+
+    pub fn stderr(&self, text: impl Into<String>) {
+        self.stream(StreamName::Stderr, text);
+    }
+
+    pub fn stream(&self, name: StreamName, text: impl Into<String>) {
+        let content = Stream {
+            name,
+            text: text.into(),
+        };
+        self.iopub.publish_to(&self.parent, "stream", content, Vec::new());
+    }
+
+    /// Shows `value` in the cell's output. With a `display_id`, the output can
+    /// later be replaced through [`JuExecContext::update_display_data`].
+    ///
+    /// Clients older than protocol 5.1 get no `transient` field, so the
+    /// display id is not sent to them.
+    pub fn display_data(&self, value: EvalValue, display_id: Option<&str>) {
+        let transient = match display_id {
+            Some(id) => json!({ "display_id": id }),
+            None => json!({}),
+        };
+        let content = Value::try_from(DisplayData {
+            data: value.data,
+            metadata: value.metadata,
+            transient,
+        });
+        let mut content = match content {
+            Ok(content) => content,
+            Err(e) => {
+                error!("Dropping display_data whose content does not serialize: {}", e);
+                return;
+            }
+        };
+        if !self.iopub.protocol_for(&self.parent).has_update_display_data()
+            && let Some(content) = content.as_object_mut()
+        {
+            content.remove("transient");
+        }
+        self.iopub
+            .publish_to(&self.parent, "display_data", content, value.buffers);
+    }
+
+    /// Replaces every output shown with `display_id`, in any cell.
+    ///
+    /// Dropped, with a warning, for clients older than protocol 5.1, which do
+    /// not know the message.
+    pub fn update_display_data(&self, value: EvalValue, display_id: &str) {
+        let protocol = self.iopub.protocol_for(&self.parent);
+        if !protocol.has_update_display_data() {
+            warn!("Dropping update_display_data, which protocol {} does not support", protocol);
+            return;
+        }
+        let content = UpdateDisplayData {
+            data: value.data,
+            metadata: value.metadata,
+            transient: json!({ "display_id": display_id }),
+        };
+        self.iopub
+            .publish_to(&self.parent, "update_display_data", content, value.buffers);
+    }
+
+    /// Clears the cell's output. With `wait`, the frontend clears it only
+    /// when the next output arrives, which avoids flicker.
+    pub fn clear_output(&self, wait: bool) {
+        self.iopub
+            .publish_to(&self.parent, "clear_output", ClearOutput { wait }, Vec::new());
+    }
+
+    /// Asks the user for input. See [`JuServerHandle::input`].
+    ///
+    /// [`JuServerHandle::input`]: crate::JuServerHandle::input
+    pub async fn input(&self, prompt: &str, password: bool) -> JuResult<String> {
+        match &self.stdin {
+            Some(stdin) => stdin.input(prompt, password).await,
+            None => Err(JuError::StdinNotAllowed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server_id::JuServerId;
+
+    #[test]
+    fn outputs_are_children_of_the_request() {
+        let jsi = JuServerId::for_test();
+        let (iopub, mut rx) = IoPub::channel(jsi.clone());
+
+        let request = jsi.new_header("execute_request");
+        let ctx = JuExecContext::new(
+            iopub,
+            None,
+            request.clone(),
+            3,
+            json!({ "cellId": "c-1" }),
+            CancellationToken::new(),
+        );
+        assert_eq!(ctx.execution_count(), 3);
+        assert_eq!(ctx.cell_id(), Some("c-1"));
+
+        let png = EvalValue {
+            data: json!({ "image/png": "..." }),
+            metadata: json!({}),
+            buffers: Vec::new(),
+        };
+        ctx.stdout("working\n");
+        ctx.display_data(png, Some("progress"));
+        ctx.update_display_data(
+            EvalValue {
+                data: json!({ "text/plain": "done" }),
+                metadata: json!({}),
+                buffers: Vec::new(),
+            },
+            "progress",
+        );
+        ctx.clear_output(true);
+
+        let sent: Vec<_> = std::iter::from_fn(|| rx.try_recv().ok()).collect();
+        let types: Vec<_> = sent.iter().map(|m| m.msg_type()).collect();
+        assert_eq!(types, ["stream", "display_data", "update_display_data", "clear_output"]);
+        assert!(sent.iter().all(|m| m.parent_header.as_ref() == Some(&request)));
+
+        assert_eq!(sent[0].content, json!({ "name": "stdout", "text": "working\n" }));
+        assert_eq!(sent[1].content["transient"], json!({ "display_id": "progress" }));
+        assert_eq!(sent[2].content["transient"], json!({ "display_id": "progress" }));
+        assert_eq!(sent[3].content, json!({ "wait": true }));
+    }
+
+    #[test]
+    fn protocol_5_0_gets_no_display_updates() {
+        let jsi = JuServerId::for_test();
+        let (iopub, mut rx) = IoPub::channel(jsi.clone());
+
+        let mut request = jsi.new_header("execute_request");
+        request.version = "5.0".to_string();
+        jsi.protocol.observe(&request);
+        let ctx = JuExecContext::new(iopub, None, request, 1, json!({}), CancellationToken::new());
+
+        let text = |s: &str| EvalValue {
+            data: json!({ "text/plain": s }),
+            metadata: json!({}),
+            buffers: Vec::new(),
+        };
+        ctx.display_data(text("working"), Some("progress"));
+        ctx.update_display_data(text("done"), "progress");
+
+        let sent: Vec<_> = std::iter::from_fn(|| rx.try_recv().ok()).collect();
+        let types: Vec<_> = sent.iter().map(|m| m.msg_type()).collect();
+        assert_eq!(types, ["display_data"]);
+        assert_eq!(sent[0].content.get("transient"), None);
+    }
+
+    #[test]
+    fn other_sessions_do_not_lower_the_protocol() {
+        let jsi = JuServerId::for_test();
+        let (iopub, mut rx) = IoPub::channel(jsi.clone());
+
+        let mut old = jsi.new_header("execute_request");
+        old.session = "old-frontend".to_string();
+        old.version = "5.0".to_string();
+        jsi.protocol.observe(&old);
+        let request = jsi.new_header("execute_request");
+        jsi.protocol.observe(&request);
+        let ctx = JuExecContext::new(iopub, None, request, 1, json!({}), CancellationToken::new());
+
+        let text = EvalValue {
+            data: json!({ "text/plain": "done" }),
+            metadata: json!({}),
+            buffers: Vec::new(),
+        };
+        ctx.update_display_data(text, "progress");
+
+        let sent: Vec<_> = std::iter::from_fn(|| rx.try_recv().ok()).collect();
+        let types: Vec<_> = sent.iter().map(|m| m.msg_type()).collect();
+        assert_eq!(types, ["update_display_data"]);
+    }
+}
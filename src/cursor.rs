@@ -0,0 +1,62 @@
+//! Conversions between protocol cursor positions and byte offsets.
+//!
+//! Since protocol 5.2 cursor positions count Unicode code points; before that
+//! frontends sent UTF-16 code units. Kernels always see byte offsets into the
+//! UTF-8 code, which is what slicing a `str` needs.
+
+/// Converts a protocol cursor position into a byte offset into `code`,
+/// clamped to the end of the string.
+pub(crate) fn to_byte_offset(code: &str, pos: usize, code_points: bool) -> usize {
+    let mut units = 0;
+    for (offset, c) in code.char_indices() {
+        if units >= pos {
+            return offset;
+        }
+        units += if code_points { 1 } else { c.len_utf16() };
+    }
+    code.len()
+}
+
+/// Converts a byte offset into `code` into a protocol cursor position. Offsets
+/// inside a character count as the start of that character.
+pub(crate) fn from_byte_offset(code: &str, offset: usize, code_points: bool) -> usize {
+    code.char_indices()
+        .take_while(|(i, c)| i + c.len_utf8() <= offset)
+        .map(|(_, c)| if code_points { 1 } else { c.len_utf16() })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ascii_positions_are_unchanged() {
+        assert_eq!(to_byte_offset("abc", 2, true), 2);
+        assert_eq!(from_byte_offset("abc", 2, true), 2);
+        assert_eq!(to_byte_offset("abc", 10, true), 3);
+    }
+
+    #[test]
+    fn code_points() {
+        // 'é' is 2 bytes, '😀' is 4 bytes and 2 UTF-16 units.
+        let code = "é😀x";
+        assert_eq!(to_byte_offset(code, 1, true), 2);
+        assert_eq!(to_byte_offset(code, 2, true), 6);
+        assert_eq!(from_byte_offset(code, 6, true), 2);
+        assert_eq!(from_byte_offset(code, 7, true), 3);
+    }
+
+    #[test]
+    fn utf16_units() {
+        let code = "é😀x";
+        assert_eq!(to_byte_offset(code, 3, false), 6);
+        assert_eq!(from_byte_offset(code, 6, false), 3);
+        assert_eq!(from_byte_offset(code, 7, false), 4);
+    }
+
+    #[test]
+    fn offsets_inside_a_character_round_down() {
+        assert_eq!(from_byte_offset("😀x", 2, true), 0);
+    }
+}
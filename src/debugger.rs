@@ -0,0 +1,293 @@
+use std::sync::{
+    Arc, Mutex,
+    atomic::{AtomicU64, Ordering},
+};
+
+use serde_json::{Value, json};
+use tracing::{debug, warn};
+
+use crate::{iopub::IoPub, protocol::Header};
+
+/// The body of a successful DAP response, or the error message of a failed
+/// one.
+pub type JuDapResult = Result<Value, String>;
+
+/// A Debug Adapter Protocol implementation, as driven by JupyterLab's visual
+/// debugger through `debug_request`s on the control channel.
+///
+/// Each method takes the request's `arguments` and returns the response
+/// `body`. Requests run on the control channel, so they are answered while
+/// code is executing, e.g. stopped at a breakpoint. Events such as `stopped`
+/// go out through [`JuServerHandle::debug_event`](crate::JuServerHandle::debug_event).
+///
+/// Unless noted otherwise, the defaults pass the request on to
+/// [`JuDebugger::other`], which fails it.
+pub trait JuDebugger: Send {
+    /// Returns the adapter's capabilities. An `initialized` event follows a
+    /// successful reply.
+    fn initialize(&mut self, args: Value) -> JuDapResult;
+
+    /// Succeeds with no body by default.
+    fn attach(&mut self, _args: Value) -> JuDapResult {
+        Ok(Value::Null)
+    }
+
+    /// Succeeds with no body by default.
+    fn configuration_done(&mut self, _args: Value) -> JuDapResult {
+        Ok(Value::Null)
+    }
+
+    /// Succeeds with no body by default.
+    fn disconnect(&mut self, _args: Value) -> JuDapResult {
+        Ok(Value::Null)
+    }
+
+    /// Replaces the breakpoints of one source, returning them as verified
+    /// (or not).
+    fn set_breakpoints(&mut self, args: Value) -> JuDapResult {
+        self.other("setBreakpoints", args)
+    }
+
+    /// Succeeds with no body by default.
+    fn set_exception_breakpoints(&mut self, _args: Value) -> JuDapResult {
+        Ok(Value::Null)
+    }
+
+    /// `continue`: resumes a stopped thread.
+    fn resume(&mut self, args: Value) -> JuDapResult {
+        self.other("continue", args)
+    }
+
+    fn next(&mut self, args: Value) -> JuDapResult {
+        self.other("next", args)
+    }
+
+    fn step_in(&mut self, args: Value) -> JuDapResult {
+        self.other("stepIn", args)
+    }
+
+    fn step_out(&mut self, args: Value) -> JuDapResult {
+        self.other("stepOut", args)
+    }
+
+    fn pause(&mut self, args: Value) -> JuDapResult {
+        self.other("pause", args)
+    }
+
+    /// A single thread with id 1 by default.
+    fn threads(&mut self, _args: Value) -> JuDapResult {
+        Ok(json!({ "threads": [{ "id": 1, "name": "main" }] }))
+    }
+
+    fn stack_trace(&mut self, args: Value) -> JuDapResult {
+        self.other("stackTrace", args)
+    }
+
+    fn scopes(&mut self, args: Value) -> JuDapResult {
+        self.other("scopes", args)
+    }
+
+    fn variables(&mut self, args: Value) -> JuDapResult {
+        self.other("variables", args)
+    }
+
+    fn source(&mut self, args: Value) -> JuDapResult {
+        self.other("source", args)
+    }
+
+    fn evaluate(&mut self, args: Value) -> JuDapResult {
+        self.other("evaluate", args)
+    }
+
+    /// Jupyter extension: stores a cell's `code` as a source file, so that
+    /// breakpoints can be set in it, and returns its `sourcePath`.
+    fn dump_cell(&mut self, args: Value) -> JuDapResult {
+        self.other("dumpCell", args)
+    }
+
+    /// Jupyter extension: the debugger state a frontend needs after
+    /// connecting, including how cell source paths are derived and the
+    /// current breakpoints.
+    fn debug_info(&mut self, args: Value) -> JuDapResult {
+        self.other("debugInfo", args)
+    }
+
+    /// Jupyter extension: the global variables, for the variable explorer.
+    fn inspect_variables(&mut self, args: Value) -> JuDapResult {
+        self.other("inspectVariables", args)
+    }
+
+    /// Any request without a method of its own.
+    fn other(&mut self, command: &str, _args: Value) -> JuDapResult {
+        Err(format!("Unsupported debug request {command:?}"))
+    }
+}
+
+/// Routes `debug_request`s to the kernel's [`JuDebugger`] and numbers the
+/// responses and events.
+#[derive(Clone)]
+pub(crate) struct Debugger {
+    inner: Arc<Mutex<Box<dyn JuDebugger>>>,
+    iopub: IoPub,
+    seq: Arc<AtomicU64>,
+}
+
+impl Debugger {
+    pub(crate) fn new(debugger: Box<dyn JuDebugger>, iopub: IoPub, seq: Arc<AtomicU64>) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(debugger)),
+            iopub,
+            seq,
+        }
+    }
+
+    /// Answers the DAP `request`, which arrived in the message `parent`.
+    pub(crate) fn handle(&self, parent: &Header, request: Value) -> Value {
+        let command = request["command"].as_str().unwrap_or_default().to_string();
+        let args = request.get("arguments").cloned().unwrap_or(Value::Null);
+        debug!("Debug request {:?}", command);
+
+        let res = {
+            let mut debugger = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+            match command.as_str() {
+                "initialize" => debugger.initialize(args),
+                "attach" => debugger.attach(args),
+                "configurationDone" => debugger.configuration_done(args),
+                "disconnect" => debugger.disconnect(args),
+                "setBreakpoints" => debugger.set_breakpoints(args),
+                "setExceptionBreakpoints" => debugger.set_exception_breakpoints(args),
+                "continue" => debugger.resume(args),
+                "next" => debugger.next(args),
+                "stepIn" => debugger.step_in(args),
+                "stepOut" => debugger.step_out(args),
+                "pause" => debugger.pause(args),
+                "threads" => debugger.threads(args),
+                "stackTrace" => debugger.stack_trace(args),
+                "scopes" => debugger.scopes(args),
+                "variables" => debugger.variables(args),
+                "source" => debugger.source(args),
+                "evaluate" => debugger.evaluate(args),
+                "dumpCell" => debugger.dump_cell(args),
+                "debugInfo" => debugger.debug_info(args),
+                "inspectVariables" => debugger.inspect_variables(args),
+                other => debugger.other(other, args),
+            }
+        };
+
+        let initialized = command == "initialize" && res.is_ok();
+        let response = response(next_seq(&self.seq), &request, res);
+        if initialized {
+            self.iopub
+                .publish_to(parent, "debug_event", event(next_seq(&self.seq), "initialized", Value::Null), Vec::new());
+        }
+        response
+    }
+}
+
+pub(crate) fn next_seq(seq: &AtomicU64) -> u64 {
+    seq.fetch_add(1, Ordering::SeqCst) + 1
+}
+
+/// The DAP response to `request`.
+pub(crate) fn response(seq: u64, request: &Value, res: JuDapResult) -> Value {
+    let mut response = json!({
+        "seq": seq,
+        "type": "response",
+        "request_seq": request["seq"],
+        "command": request["command"],
+    });
+    match res {
+        Ok(body) => {
+            response["success"] = true.into();
+            if !body.is_null() {
+                response["body"] = body;
+            }
+        }
+        Err(message) => {
+            warn!("Debug request {} failed: {}", request["command"], message);
+            response["success"] = false.into();
+            response["message"] = message.into();
+        }
+    }
+    response
+}
+
+/// A DAP event numbered `seq`.
+pub(crate) fn event(seq: u64, event: &str, body: Value) -> Value {
+    let mut event = json!({
+        "seq": seq,
+        "type": "event",
+        "event": event,
+    });
+    if !body.is_null() {
+        event["body"] = body;
+    }
+    event
+}
+
+/// Publishes a DAP event as a child of the request being handled.
+pub(crate) fn publish_event(iopub: &IoPub, seq: &AtomicU64, name: &str, body: Value) {
+    iopub.publish("debug_event", event(next_seq(seq), name, body), Vec::new());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server_id::JuServerId;
+
+    struct Breakpoints(Vec<u64>);
+
+    impl JuDebugger for Breakpoints {
+        fn initialize(&mut self, _args: Value) -> JuDapResult {
+            Ok(json!({ "supportsConfigurationDoneRequest": true }))
+        }
+
+        fn set_breakpoints(&mut self, args: Value) -> JuDapResult {
+            self.0 = args["breakpoints"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(|bp| bp["line"].as_u64())
+                .collect();
+            let verified: Vec<Value> = self.0.iter().map(|line| json!({ "verified": true, "line": line })).collect();
+            Ok(json!({ "breakpoints": verified }))
+        }
+    }
+
+    #[test]
+    fn routes_requests_and_numbers_replies() {
+        let jsi = JuServerId::for_test();
+        let parent = jsi.new_header("debug_request");
+        let (iopub, mut rx) = IoPub::channel(jsi);
+        let debugger = Debugger::new(Box::new(Breakpoints(Vec::new())), iopub, Default::default());
+
+        let reply = debugger.handle(&parent, json!({ "seq": 1, "type": "request", "command": "initialize" }));
+        assert_eq!(reply["seq"], 1);
+        assert_eq!(reply["request_seq"], 1);
+        assert_eq!(reply["success"], true);
+        assert_eq!(reply["body"]["supportsConfigurationDoneRequest"], true);
+
+        let initialized = rx.try_recv().unwrap();
+        assert_eq!(initialized.msg_type(), "debug_event");
+        assert_eq!(initialized.content, json!({ "seq": 2, "type": "event", "event": "initialized" }));
+        assert_eq!(initialized.parent_header.unwrap().msg_id, parent.msg_id);
+
+        let reply = debugger.handle(
+            &parent,
+            json!({
+                "seq": 2,
+                "type": "request",
+                "command": "setBreakpoints",
+                "arguments": { "source": { "path": "/tmp/a" }, "breakpoints": [{ "line": 3 }] },
+            }),
+        );
+        assert_eq!(reply["seq"], 3);
+        assert_eq!(reply["body"]["breakpoints"][0], json!({ "verified": true, "line": 3 }));
+
+        let reply = debugger.handle(&parent, json!({ "seq": 3, "type": "request", "command": "stepBack" }));
+        assert_eq!(reply["success"], false);
+        assert_eq!(reply["command"], "stepBack");
+        assert!(reply["message"].as_str().unwrap().contains("stepBack"));
+        assert!(rx.try_recv().is_err());
+    }
+}
@@ -1,37 +1,251 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, OnceLock, RwLock},
+};
+
 use bytes::Bytes;
+use hmac::{Hmac, Mac};
 
 use crate::{ConnectionInfo, JuError, JuResult};
 
+/// A message signing algorithm, keyed once per session.
+///
+/// `sign` receives the header, parent header, metadata and content frames in
+/// wire order and returns the raw (not hex-encoded) signature.
+pub trait JuSigner: Send + Sync {
+    fn sign(&self, parts: &[&[u8]]) -> Vec<u8>;
+}
+
+type SignerFactory = Arc<dyn Fn(&[u8]) -> Box<dyn JuSigner> + Send + Sync>;
+
+fn custom_schemes() -> &'static RwLock<HashMap<String, SignerFactory>> {
+    static SCHEMES: OnceLock<RwLock<HashMap<String, SignerFactory>>> = OnceLock::new();
+    SCHEMES.get_or_init(Default::default)
+}
+
+/// Makes `name` usable as a `signature_scheme` in connection files.
+///
+/// The factory is called with the session key. Registering a name twice, or
+/// registering one of the built-in `hmac-*` names, replaces the previous
+/// implementation.
+pub fn register_signature_scheme<F>(name: impl Into<String>, factory: F)
+where
+    F: Fn(&[u8]) -> Box<dyn JuSigner> + Send + Sync + 'static,
+{
+    custom_schemes()
+        .write()
+        .unwrap_or_else(|e| e.into_inner())
+        .insert(name.into(), Arc::new(factory));
+}
+
+struct HmacSigner<M>(M);
+
+impl<M: Mac + hmac::digest::KeyInit + Clone + Send + Sync> HmacSigner<M> {
+    fn boxed(key: &[u8]) -> Box<dyn JuSigner>
+    where
+        M: 'static,
+    {
+        let mac = <M as Mac>::new_from_slice(key).expect("HMAC accepts keys of any length");
+        Box::new(Self(mac))
+    }
+}
+
+impl<M: Mac + Clone + Send + Sync> JuSigner for HmacSigner<M> {
+    fn sign(&self, parts: &[&[u8]]) -> Vec<u8> {
+        let mut mac = self.0.clone();
+        for part in parts {
+            mac.update(part);
+        }
+        mac.finalize().into_bytes().to_vec()
+    }
+}
+
+fn builtin_scheme(name: &str, key: &[u8]) -> Option<Box<dyn JuSigner>> {
+    match name {
+        "hmac-md5" => Some(HmacSigner::<Hmac<md5::Md5>>::boxed(key)),
+        "hmac-sha1" => Some(HmacSigner::<Hmac<sha1::Sha1>>::boxed(key)),
+        "hmac-sha224" => Some(HmacSigner::<Hmac<sha2::Sha224>>::boxed(key)),
+        "hmac-sha256" => Some(HmacSigner::<Hmac<sha2::Sha256>>::boxed(key)),
+        "hmac-sha384" => Some(HmacSigner::<Hmac<sha2::Sha384>>::boxed(key)),
+        "hmac-sha512" => Some(HmacSigner::<Hmac<sha2::Sha512>>::boxed(key)),
+        _ => None,
+    }
+}
+
 #[derive(Clone)]
-pub(crate) enum Digester {
-    HmacSha256(hmac_sha256::HMAC),
-    None,
+pub(crate) struct Digester {
+    signer: Option<Arc<dyn JuSigner>>,
 }
 
 impl Digester {
     pub(crate) fn new(ci: &ConnectionInfo) -> JuResult<Self> {
-        if ci.signature_scheme.is_empty() {
-            Ok(Self::None)
-        } else if ci.signature_scheme == "hmac-sha256" {
-            Ok(Self::HmacSha256(hmac_sha256::HMAC::new(ci.key.as_bytes())))
-        } else {
-            Err(JuError::UnknownDigest(ci.signature_scheme.clone()))
+        Self::from_scheme(&ci.signature_scheme, ci.key.expose().as_bytes())
+    }
+
+    pub(crate) fn from_scheme(scheme: &str, key: &[u8]) -> JuResult<Self> {
+        if scheme.is_empty() {
+            return Ok(Self { signer: None });
         }
+
+        let custom = custom_schemes()
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(scheme)
+            .cloned();
+
+        let signer = match custom {
+            Some(factory) => factory(key),
+            None => builtin_scheme(scheme, key)
+                .ok_or_else(|| JuError::UnknownDigest(scheme.to_string()))?,
+        };
+
+        Ok(Self {
+            signer: Some(signer.into()),
+        })
+    }
+
+    /// Whether messages carry a signature, i.e. a signature scheme is set.
+    pub(crate) fn is_signed(&self) -> bool {
+        self.signer.is_some()
     }
 
     pub(crate) fn digest(&self, d1: &Bytes, d2: &Bytes, d3: &Bytes, d4: &Bytes) -> Bytes {
-        match self {
-            Digester::HmacSha256(hmac) => {
-                let mut mac = hmac.clone();
-                mac.update(d1);
-                mac.update(d2);
-                mac.update(d3);
-                mac.update(d4);
-
-                let hex = hex::encode(mac.finalize().as_slice());
+        match &self.signer {
+            Some(signer) => {
+                let hex = hex::encode(signer.sign(&[d1, d2, d3, d4]));
                 hex.into()
             }
-            Digester::None => Bytes::new(),
+            None => Bytes::new(),
+        }
+    }
+
+    /// Checks `sig` against the digest of the four signed frames.
+    ///
+    /// Unsigned sessions (empty signature scheme) accept any signature, as the
+    /// Jupyter spec requires.
+    pub(crate) fn verify(&self, sig: &[u8], d1: &Bytes, d2: &Bytes, d3: &Bytes, d4: &Bytes) -> bool {
+        match &self.signer {
+            None => true,
+            Some(_) => constant_time_eq(&self.digest(d1, d2, d3, d4), sig),
+        }
+    }
+}
+
+/// Compares two byte strings without short-circuiting on the first mismatch.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // "The quick brown fox jumps over the lazy dog", split over four frames.
+    fn frames() -> [Bytes; 4] {
+        [
+            Bytes::from_static(b"The quick "),
+            Bytes::from_static(b"brown fox "),
+            Bytes::from_static(b"jumps over "),
+            Bytes::from_static(b"the lazy dog"),
+        ]
+    }
+
+    fn check_scheme(scheme: &str, expected: &str) {
+        let digester = Digester::from_scheme(scheme, b"key").unwrap();
+        let [d1, d2, d3, d4] = frames();
+
+        let sig = digester.digest(&d1, &d2, &d3, &d4);
+        assert_eq!(sig, expected.as_bytes(), "{scheme} signature");
+        assert!(digester.verify(&sig, &d1, &d2, &d3, &d4), "{scheme} verify");
+
+        let tampered = Bytes::from_static(b"the lazy cat");
+        assert!(!digester.verify(&sig, &d1, &d2, &d3, &tampered), "{scheme} tampered");
+    }
+
+    #[test]
+    fn hmac_md5() {
+        check_scheme("hmac-md5", "80070713463e7749b90c2dc24911e275");
+    }
+
+    #[test]
+    fn hmac_sha1() {
+        check_scheme("hmac-sha1", "de7c9b85b8b78aa6bc8a7a36f70a90701c9db4d9");
+    }
+
+    #[test]
+    fn hmac_sha224() {
+        check_scheme(
+            "hmac-sha224",
+            "88ff8b54675d39b8f72322e65ff945c52d96379988ada25639747e69",
+        );
+    }
+
+    #[test]
+    fn hmac_sha256() {
+        check_scheme(
+            "hmac-sha256",
+            "f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8",
+        );
+    }
+
+    #[test]
+    fn hmac_sha384() {
+        check_scheme(
+            "hmac-sha384",
+            "d7f4727e2c0b39ae0f1e40cc96f60242d5b7801841cea6fc\
+             592c5d3e1ae50700582a96cf35e1e554995fe4e03381c237",
+        );
+    }
+
+    #[test]
+    fn hmac_sha512() {
+        check_scheme(
+            "hmac-sha512",
+            "b42af09057bac1e2d41708e48a902e09b5ff7f12ab428a4fe86653c73dd248fb\
+             82f948a549f7b791a5b41915ee4d1ec3935357e4e2317250d0372afa2ebeeb3a",
+        );
+    }
+
+    #[test]
+    fn unsigned_scheme_accepts_anything() {
+        let digester = Digester::from_scheme("", b"").unwrap();
+        let [d1, d2, d3, d4] = frames();
+
+        assert!(digester.digest(&d1, &d2, &d3, &d4).is_empty());
+        assert!(digester.verify(b"whatever", &d1, &d2, &d3, &d4));
+    }
+
+    #[test]
+    fn unknown_scheme_is_an_error() {
+        let res = Digester::from_scheme("hmac-whirlpool", b"key");
+        assert!(matches!(res, Err(JuError::UnknownDigest(_))));
+    }
+
+    struct XorSigner(u8);
+
+    impl JuSigner for XorSigner {
+        fn sign(&self, parts: &[&[u8]]) -> Vec<u8> {
+            let x = parts.iter().flat_map(|p| p.iter()).fold(self.0, |acc, b| acc ^ b);
+            vec![x]
         }
     }
+
+    #[test]
+    fn custom_scheme() {
+        register_signature_scheme("test-xor", |key| {
+            Box::new(XorSigner(key.iter().fold(0, |acc, b| acc ^ b)))
+        });
+
+        let digester = Digester::from_scheme("test-xor", b"k").unwrap();
+        let [d1, d2, d3, d4] = frames();
+
+        let sig = digester.digest(&d1, &d2, &d3, &d4);
+        assert_eq!(sig.len(), 2);
+        assert!(digester.verify(&sig, &d1, &d2, &d3, &d4));
+        assert!(!digester.verify(b"00", &d1, &d2, &d3, &d4));
+    }
 }
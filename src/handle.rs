@@ -0,0 +1,119 @@
+use std::sync::Arc;
+
+use bytes::Bytes;
+use serde_json::{Value, json};
+
+use crate::{
+    JuError, JuResult,
+    comm::{CommManager, JuComm, JuCommHandler, JuCommTarget},
+    debugger,
+    history::{JuHistoryEntry, JuHistoryQuery},
+    iopub::IoPub,
+    server_id::JuServerId,
+    stdin::Stdin,
+    version::ProtocolVersion,
+};
+
+/// A kernel's view of the server it runs in, handed over through
+/// [`JuKernel::attach`](crate::JuKernel::attach).
+///
+/// Cheap to clone; every clone refers to the same server.
+#[derive(Clone)]
+pub struct JuServerHandle {
+    jsi: Arc<JuServerId>,
+    iopub: IoPub,
+    comms: CommManager,
+    stdin: Option<Stdin>,
+}
+
+impl JuServerHandle {
+    pub(crate) fn new(jsi: JuServerId, iopub: IoPub, comms: CommManager, stdin: Option<Stdin>) -> Self {
+        Self {
+            jsi: Arc::new(jsi),
+            iopub,
+            comms,
+            stdin,
+        }
+    }
+
+    /// The lowest protocol version negotiated with any client so far.
+    pub fn protocol_version(&self) -> ProtocolVersion {
+        self.jsi.protocol.negotiated()
+    }
+
+    /// The protocol version this server is configured to speak.
+    pub fn server_protocol_version(&self) -> ProtocolVersion {
+        self.jsi.protocol.server()
+    }
+
+    /// The current history session number.
+    pub fn history_session(&self) -> u32 {
+        self.jsi.history.lock().unwrap_or_else(|e| e.into_inner()).session()
+    }
+
+    /// Looks up execution history, as a `history_request` would.
+    pub fn history(&self, query: &JuHistoryQuery) -> Vec<JuHistoryEntry> {
+        self.jsi.history.lock().unwrap_or_else(|e| e.into_inner()).query(query)
+    }
+
+    /// Accepts comms that frontends open to `target_name`, replacing any
+    /// target registered under that name before.
+    pub fn register_comm_target(&self, target_name: impl Into<String>, target: impl JuCommTarget + 'static) {
+        self.comms.register_target(target_name.into(), Box::new(target));
+    }
+
+    /// Stops accepting new comms for `target_name`. Comms already open stay
+    /// open. Returns whether a target was registered.
+    pub fn unregister_comm_target(&self, target_name: &str) -> bool {
+        self.comms.unregister_target(target_name)
+    }
+
+    /// Opens a comm to `target_name` in the frontend, with `handler`
+    /// receiving the frontend's messages on it.
+    pub fn open_comm(
+        &self,
+        target_name: impl Into<String>,
+        data: impl Into<Value>,
+        buffers: Vec<Bytes>,
+        handler: impl JuCommHandler + 'static,
+    ) -> JuComm {
+        self.open_comm_with_metadata(target_name, data, json!({}), buffers, handler)
+    }
+
+    pub(crate) fn open_comm_with_metadata(
+        &self,
+        target_name: impl Into<String>,
+        data: impl Into<Value>,
+        metadata: Value,
+        buffers: Vec<Bytes>,
+        handler: impl JuCommHandler + 'static,
+    ) -> JuComm {
+        self.comms.open(
+            &self.iopub,
+            target_name.into(),
+            data.into(),
+            metadata,
+            buffers,
+            Box::new(handler),
+        )
+    }
+
+    /// Publishes the DAP event `event`, e.g. `stopped` when the running code
+    /// hits a breakpoint. A null `body` is left out.
+    pub fn debug_event(&self, event: &str, body: impl Into<Value>) {
+        debugger::publish_event(&self.iopub, &self.jsi.debug_seq, event, body.into());
+    }
+
+    /// Asks the user for a line of input, on behalf of the running execute
+    /// request. `password` asks the frontend to hide what is typed.
+    ///
+    /// Fails with [`JuError::StdinNotAllowed`] when no code is running or the
+    /// frontend sent `allow_stdin: false`, and with [`JuError::Interrupted`]
+    /// if the execution is interrupted while waiting.
+    pub async fn input(&self, prompt: &str, password: bool) -> JuResult<String> {
+        match &self.stdin {
+            Some(stdin) => stdin.input(prompt, password).await,
+            None => Err(JuError::StdinNotAllowed),
+        }
+    }
+}
@@ -0,0 +1,309 @@
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::{BufRead, BufReader, Write},
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use tracing::warn;
+
+use crate::{JuResult, config::JuServerConfig};
+
+/// One executed cell.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct JuHistoryEntry {
+    /// Numbered from 1; every server start or restart begins a new session.
+    pub session: u32,
+    /// The execution count the input ran as.
+    pub line: u32,
+    pub input: String,
+    /// The `text/plain` form of the result, if there was one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub output: Option<String>,
+}
+
+/// A history lookup, with the meanings `history_request` gives its fields.
+#[derive(Debug, Clone, PartialEq)]
+pub enum JuHistoryQuery {
+    /// The last `n` entries across all sessions.
+    Tail { n: usize },
+    /// Lines `start..stop` of one session, where `stop: None` reads to the
+    /// end. `session` is absolute when positive, and relative to the current
+    /// session when 0 or negative.
+    Range { session: i64, start: u32, stop: Option<u32> },
+    /// The last `n` inputs matching the glob `pattern` (`*` and `?`), all of
+    /// them when `n` is `None`. With `unique`, only the latest of identical
+    /// inputs is kept.
+    Search { pattern: String, n: Option<usize>, unique: bool },
+}
+
+/// Execution history of this and, with a history file, earlier sessions.
+pub(crate) struct JuHistory {
+    session: u32,
+    entries: Vec<JuHistoryEntry>,
+    file: Option<File>,
+}
+
+impl JuHistory {
+    /// Loads earlier sessions from `config.history_file`, if set, and starts
+    /// a new session after them.
+    pub(crate) fn open(config: &JuServerConfig) -> JuResult<Self> {
+        let mut entries = Vec::new();
+        let mut file = None;
+
+        if let Some(path) = &config.history_file {
+            if let Some(dir) = path.parent() {
+                std::fs::create_dir_all(dir)?;
+            }
+
+            // Inputs and outputs may hold secrets, so a new file is made
+            // readable by the current user only, like connection files.
+            let mut options = std::fs::OpenOptions::new();
+            options.read(true).append(true).create(true);
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::OpenOptionsExt;
+                options.mode(0o600);
+            }
+
+            let f = options.open(path)?;
+
+            for line in BufReader::new(&f).lines() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                match serde_json::from_str(&line) {
+                    Ok(entry) => entries.push(entry),
+                    Err(e) => warn!("Skipping unreadable history entry in {}: {e}", path.display()),
+                }
+            }
+            file = Some(f);
+        }
+
+        let session = entries.iter().map(|e: &JuHistoryEntry| e.session).max().unwrap_or(0) + 1;
+        Ok(Self {
+            session,
+            entries,
+            file,
+        })
+    }
+
+    pub(crate) fn session(&self) -> u32 {
+        self.session
+    }
+
+    /// Records later entries under the next session number.
+    pub(crate) fn new_session(&mut self) {
+        self.session += 1;
+    }
+
+    pub(crate) fn record(&mut self, line: u32, input: String, output: Option<String>) {
+        let entry = JuHistoryEntry {
+            session: self.session,
+            line,
+            input,
+            output,
+        };
+
+        if let Some(f) = &mut self.file {
+            let res = serde_json::to_vec(&entry)
+                .map_err(std::io::Error::from)
+                .and_then(|mut buf| {
+                    buf.push(b'\n');
+                    f.write_all(&buf)
+                });
+            if let Err(e) = res {
+                warn!("Failed to persist history entry: {e}");
+            }
+        }
+
+        self.entries.push(entry);
+    }
+
+    pub(crate) fn query(&self, query: &JuHistoryQuery) -> Vec<JuHistoryEntry> {
+        match query {
+            JuHistoryQuery::Tail { n } => {
+                let skip = self.entries.len().saturating_sub(*n);
+                self.entries[skip..].to_vec()
+            }
+            JuHistoryQuery::Range {
+                session,
+                start,
+                stop,
+            } => {
+                let session = if *session > 0 {
+                    *session
+                } else {
+                    i64::from(self.session) + session
+                };
+                self.entries
+                    .iter()
+                    .filter(|e| i64::from(e.session) == session)
+                    .filter(|e| e.line >= *start && stop.is_none_or(|stop| e.line < stop))
+                    .cloned()
+                    .collect()
+            }
+            JuHistoryQuery::Search { pattern, n, unique } => {
+                let mut seen = HashSet::new();
+                let mut found: Vec<_> = self
+                    .entries
+                    .iter()
+                    .rev()
+                    .filter(|e| glob_match(pattern, &e.input))
+                    .filter(|e| !unique || seen.insert(e.input.as_str()))
+                    .take(n.unwrap_or(usize::MAX))
+                    .cloned()
+                    .collect();
+                found.reverse();
+                found
+            }
+        }
+    }
+}
+
+impl JuHistoryEntry {
+    /// The `history_reply` form: `[session, line, input]`, or with `output`
+    /// `[session, line, [input, output]]`.
+    pub(crate) fn to_reply_value(&self, output: bool) -> Value {
+        if output {
+            json!([self.session, self.line, [self.input, self.output]])
+        } else {
+            json!([self.session, self.line, self.input])
+        }
+    }
+}
+
+/// Matches `text` against a glob where `*` matches any run of characters and
+/// `?` any single character.
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack = None;
+
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((bp, bt)) => {
+                    p = bp + 1;
+                    t = bt + 1;
+                    backtrack = Some((bp, bt + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::*;
+
+    fn in_memory() -> JuHistory {
+        JuHistory::open(&JuServerConfig::default()).unwrap()
+    }
+
+    #[test]
+    fn glob() {
+        assert!(glob_match("*", ""));
+        assert!(glob_match("print*", "print(1)"));
+        assert!(glob_match("*(?)", "print(1)"));
+        assert!(glob_match("a*b*c", "aXbYbZc"));
+        assert!(!glob_match("print", "print(1)"));
+        assert!(!glob_match("a?c", "ac"));
+    }
+
+    #[test]
+    fn tail_and_search() {
+        let mut history = in_memory();
+        history.record(1, "x = 1".into(), None);
+        history.record(2, "print(x)".into(), Some("1".into()));
+        history.record(3, "x = 1".into(), None);
+
+        let tail = history.query(&JuHistoryQuery::Tail { n: 2 });
+        assert_eq!(tail.iter().map(|e| e.line).collect::<Vec<_>>(), [2, 3]);
+
+        let all = history.query(&JuHistoryQuery::Search {
+            pattern: "x = *".into(),
+            n: None,
+            unique: false,
+        });
+        assert_eq!(all.len(), 2);
+
+        let unique = history.query(&JuHistoryQuery::Search {
+            pattern: "*".into(),
+            n: None,
+            unique: true,
+        });
+        assert_eq!(unique.iter().map(|e| e.line).collect::<Vec<_>>(), [2, 3]);
+    }
+
+    #[test]
+    fn persists_across_sessions() {
+        let path = std::env::temp_dir().join(format!("juker-history-{}.jsonl", Uuid::new_v4()));
+        let config = JuServerConfig {
+            history_file: Some(path.clone()),
+            ..Default::default()
+        };
+
+        let mut first = JuHistory::open(&config).unwrap();
+        assert_eq!(first.session(), 1);
+        first.record(1, "a".into(), None);
+        first.record(2, "b".into(), Some("2".into()));
+        drop(first);
+
+        let mut second = JuHistory::open(&config).unwrap();
+        second.record(1, "c".into(), None);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(second.session(), 2);
+        let previous = second.query(&JuHistoryQuery::Range {
+            session: -1,
+            start: 2,
+            stop: None,
+        });
+        assert_eq!(previous.len(), 1);
+        assert_eq!(previous[0].to_reply_value(true), json!([1, 2, ["b", "2"]]));
+
+        let current = second.query(&JuHistoryQuery::Range {
+            session: 0,
+            start: 0,
+            stop: None,
+        });
+        assert_eq!(current[0].to_reply_value(false), json!([2, 1, "c"]));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn history_file_is_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = std::env::temp_dir().join(format!("juker-history-{}.jsonl", Uuid::new_v4()));
+        let config = JuServerConfig {
+            history_file: Some(path.clone()),
+            ..Default::default()
+        };
+
+        let history = JuHistory::open(&config).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        drop(history);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(mode & 0o777, 0o600);
+    }
+}
@@ -0,0 +1,53 @@
+use std::sync::{Arc, Mutex};
+
+use tokio_util::sync::CancellationToken;
+
+/// Hands out a cancellation token for each execution and cancels the running
+/// one on interrupt.
+#[derive(Clone, Default)]
+pub(crate) struct Interrupter {
+    current: Arc<Mutex<Option<CancellationToken>>>,
+}
+
+impl Interrupter {
+    /// A fresh token for an execution that is about to start.
+    pub(crate) fn begin(&self) -> CancellationToken {
+        let token = CancellationToken::new();
+        *self.current.lock().unwrap_or_else(|e| e.into_inner()) = Some(token.clone());
+        token
+    }
+
+    pub(crate) fn end(&self) {
+        *self.current.lock().unwrap_or_else(|e| e.into_inner()) = None;
+    }
+
+    /// Cancels the running execution, returning whether there was one.
+    pub(crate) fn interrupt(&self) -> bool {
+        match &*self.current.lock().unwrap_or_else(|e| e.into_inner()) {
+            Some(token) => {
+                token.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interrupts_only_the_running_execution() {
+        let interrupter = Interrupter::default();
+        assert!(!interrupter.interrupt());
+
+        let first = interrupter.begin();
+        interrupter.end();
+        let second = interrupter.begin();
+        assert!(interrupter.interrupt());
+
+        assert!(!first.is_cancelled());
+        assert!(second.is_cancelled());
+    }
+}
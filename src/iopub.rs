@@ -0,0 +1,154 @@
+use std::sync::{Arc, Mutex};
+
+use bytes::Bytes;
+use serde::Serialize;
+use serde_json::Value;
+use tokio::{
+    select,
+    sync::{mpsc, oneshot},
+};
+use tracing::{debug, error, warn};
+
+use crate::{JuMessage, protocol::Header, server_id::JuServerId, sockets::HBSocket, version::ProtocolVersion};
+
+/// Publishes on the IOPub socket from anywhere in the server.
+///
+/// Messages are queued to a single task that owns the socket, so they go out
+/// in the order they were sent without the sender having to await.
+#[derive(Clone)]
+pub(crate) struct IoPub {
+    tx: mpsc::UnboundedSender<JuMessage>,
+    jsi: JuServerId,
+    parent: Arc<Mutex<Option<Header>>>,
+    /// Asks the socket task to close the socket once the messages sent
+    /// before are out. `None` for [`IoPub::channel`].
+    close: Option<mpsc::UnboundedSender<oneshot::Sender<()>>>,
+}
+
+impl IoPub {
+    pub(crate) fn spawn(mut sock: HBSocket<zeromq::PubSocket>, jsi: JuServerId) -> Self {
+        let (mut iopub, mut rx) = Self::channel(jsi);
+        let (close_tx, mut close_rx) = mpsc::unbounded_channel::<oneshot::Sender<()>>();
+        iopub.close = Some(close_tx);
+        let jsi = iopub.jsi.clone();
+
+        tokio::spawn(async move {
+            loop {
+                select! {
+                    biased;
+                    msg = rx.recv() => match msg {
+                        Some(msg) => deliver(&mut sock, &jsi, msg).await,
+                        None => break,
+                    },
+                    Some(done) = close_rx.recv() => {
+                        while let Ok(msg) = rx.try_recv() {
+                            deliver(&mut sock, &jsi, msg).await;
+                        }
+                        sock.close().await;
+                        let _ = done.send(());
+                        break;
+                    }
+                }
+            }
+            debug!("IOPub closed, exiting iopub loop");
+        });
+
+        iopub
+    }
+
+    /// An `IoPub` whose messages end up in the returned receiver instead of
+    /// on a socket.
+    pub(crate) fn channel(jsi: JuServerId) -> (Self, mpsc::UnboundedReceiver<JuMessage>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let iopub = Self {
+            tx,
+            jsi,
+            parent: Default::default(),
+            close: None,
+        };
+        (iopub, rx)
+    }
+
+    /// [`IoPub::channel`] for a [`JuServerId::for_test`].
+    #[cfg(test)]
+    pub(crate) fn test_channel() -> (Self, mpsc::UnboundedReceiver<JuMessage>) {
+        Self::channel(JuServerId::for_test())
+    }
+
+    pub(crate) fn send(&self, msg: JuMessage) {
+        if self.tx.send(msg).is_err() {
+            warn!("IOPub is closed, dropping message");
+        }
+    }
+
+    /// Sends everything sent so far, then closes the socket. Later messages
+    /// are dropped.
+    pub(crate) async fn close(&self) {
+        let Some(close) = &self.close else {
+            return;
+        };
+
+        let (done_tx, done_rx) = oneshot::channel();
+        if close.send(done_tx).is_ok() {
+            let _ = done_rx.await;
+        }
+    }
+
+    /// The protocol version that replies to `parent` are published in.
+    pub(crate) fn protocol_for(&self, parent: &Header) -> ProtocolVersion {
+        self.jsi.protocol.negotiated_for(parent)
+    }
+
+    /// Sets the request that [`IoPub::publish`] attributes messages to.
+    pub(crate) fn set_parent(&self, parent: Option<Header>) {
+        *self.parent.lock().unwrap_or_else(|e| e.into_inner()) = parent;
+    }
+
+    /// A new message whose parent is the request currently being handled.
+    pub(crate) fn child_message(&self, msg_type: &str) -> JuMessage {
+        let mut msg = self.jsi.new_message(msg_type);
+        msg.parent_header = self.parent.lock().unwrap_or_else(|e| e.into_inner()).clone();
+        msg
+    }
+
+    /// Publishes `content` as a child of `parent`. Content that does not
+    /// serialize is logged and dropped.
+    pub(crate) fn publish_to(&self, parent: &Header, msg_type: &str, content: impl Serialize, buffers: Vec<Bytes>) {
+        let Some(content) = serialize(msg_type, content) else {
+            return;
+        };
+        let mut msg = self
+            .jsi
+            .new_message(msg_type)
+            .with_content(content)
+            .with_buffers(buffers);
+        msg.parent_header = Some(parent.clone());
+        self.send(msg);
+    }
+
+    /// Publishes `content` as a child of the request currently being handled.
+    /// Content that does not serialize is logged and dropped.
+    pub(crate) fn publish(&self, msg_type: &str, content: impl Serialize, buffers: Vec<Bytes>) {
+        let Some(content) = serialize(msg_type, content) else {
+            return;
+        };
+        let msg = self
+            .child_message(msg_type)
+            .with_content(content)
+            .with_buffers(buffers);
+        self.send(msg);
+    }
+}
+
+fn serialize(msg_type: &str, content: impl Serialize) -> Option<Value> {
+    serde_json::to_value(content)
+        .inspect_err(|e| error!("Dropping {} message whose content does not serialize: {}", msg_type, e))
+        .ok()
+}
+
+async fn deliver(sock: &mut HBSocket<zeromq::PubSocket>, jsi: &JuServerId, msg: JuMessage) {
+    debug!("Sending iopub message: {:?}", msg.log_view(jsi.config.redact_message_logs));
+    if let Err(e) = sock.send(msg, &jsi.digester).await {
+        error!("Failed to send iopub message: {:?}", e);
+    }
+}
@@ -0,0 +1,140 @@
+use std::{
+    collections::BTreeMap,
+    fmt,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{JuError, JuResult, con_info::jupyter_data_dir};
+
+/// How the notebook server asks the kernel to interrupt the running cell.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum InterruptMode {
+    /// SIGINT to the kernel process. Needs
+    /// [`interrupt_on_sigint`](crate::JuServerConfig::interrupt_on_sigint).
+    Signal,
+    /// An `interrupt_request` on the control channel.
+    #[default]
+    Message,
+}
+
+impl fmt::Display for InterruptMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InterruptMode::Signal => f.write_str("signal"),
+            InterruptMode::Message => f.write_str("message"),
+        }
+    }
+}
+
+impl FromStr for InterruptMode {
+    type Err = JuError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "signal" => Ok(InterruptMode::Signal),
+            "message" => Ok(InterruptMode::Message),
+            _ => Err(JuError::GeneralJukerError(format!("bad interrupt mode {s:?}"))),
+        }
+    }
+}
+
+/// The `kernel.json` that tells Jupyter how to launch a kernel.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct JuKernelSpec {
+    pub argv: Vec<String>,
+    pub display_name: String,
+    pub language: String,
+    pub interrupt_mode: InterruptMode,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub env: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "Value::is_null")]
+    pub metadata: Value,
+}
+
+impl JuKernelSpec {
+    /// A spec that launches the running executable with
+    /// `--connection-file {connection_file}` followed by `args`.
+    pub fn for_current_exe<I, S>(display_name: impl Into<String>, language: impl Into<String>, args: I) -> JuResult<Self>
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let exe = std::env::current_exe()?;
+        let mut argv = vec![
+            exe.to_string_lossy().into_owned(),
+            "--connection-file".to_string(),
+            "{connection_file}".to_string(),
+        ];
+        argv.extend(args.into_iter().map(Into::into));
+
+        Ok(Self {
+            argv,
+            display_name: display_name.into(),
+            language: language.into(),
+            interrupt_mode: InterruptMode::default(),
+            env: BTreeMap::new(),
+            metadata: Value::Null,
+        })
+    }
+
+    pub fn with_interrupt_mode(mut self, mode: InterruptMode) -> Self {
+        self.interrupt_mode = mode;
+        self
+    }
+
+    /// Writes `kernel.json` into `dir`, creating it if needed.
+    pub fn write_to_dir(&self, dir: impl AsRef<Path>) -> JuResult<PathBuf> {
+        let dir = dir.as_ref();
+        std::fs::create_dir_all(dir)?;
+
+        let path = dir.join("kernel.json");
+        let f = std::fs::File::create(&path)?;
+        serde_json::to_writer_pretty(f, self)?;
+        Ok(path)
+    }
+
+    /// Installs the spec for the current user as kernel `name`, where
+    /// `jupyter kernelspec list` finds it.
+    pub fn install(&self, name: &str) -> JuResult<PathBuf> {
+        self.write_to_dir(jupyter_data_dir().join("kernels").join(name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::*;
+
+    #[test]
+    fn writes_the_chosen_interrupt_mode() {
+        let dir = std::env::temp_dir().join(format!("juker-spec-{}", Uuid::new_v4()));
+
+        for mode in [InterruptMode::Signal, InterruptMode::Message] {
+            let spec = JuKernelSpec::for_current_exe("Juker", "nothing", ["--interrupt-mode", "signal"])
+                .unwrap()
+                .with_interrupt_mode(mode);
+            let path = spec.write_to_dir(&dir).unwrap();
+
+            let json: Value = serde_json::from_reader(std::fs::File::open(&path).unwrap()).unwrap();
+            assert_eq!(json["interrupt_mode"], mode.to_string());
+            assert_eq!(json["argv"][2], "{connection_file}");
+            assert_eq!(json["argv"][4], "signal");
+            assert!(json.get("env").is_none());
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn parses_interrupt_modes() {
+        assert_eq!("signal".parse::<InterruptMode>().unwrap(), InterruptMode::Signal);
+        assert_eq!("message".parse::<InterruptMode>().unwrap(), InterruptMode::Message);
+        assert!("sigint".parse::<InterruptMode>().is_err());
+    }
+}
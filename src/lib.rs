@@ -1,18 +1,52 @@
 pub mod message;
+pub mod protocol;
 pub mod server;
+pub mod widgets;
 mod con_info;
+mod config;
 mod sockets;
 mod digester;
 mod api;
 mod shell_processor;
 mod server_id;
+mod replay;
+mod secret;
+mod handle;
+mod version;
+mod cursor;
+mod balance;
+mod history;
+mod iopub;
+mod comm;
+mod stdin;
+mod context;
+mod interrupt;
+mod kernelspec;
+mod shutdown;
+mod debugger;
+#[cfg(test)]
+mod testing;
 
 pub use message::JuMessage;
-pub use con_info::ConnectionInfo;
-pub use api::{JuKernel, JuKernelInfo, JuHelpLink};
+pub use con_info::{ConnectionInfo, ConnectionInfoBuilder, jupyter_runtime_dir};
+pub use config::JuServerConfig;
+pub use secret::JuSecret;
+pub use handle::JuServerHandle;
+pub use version::ProtocolVersion;
+pub use api::{JuKernel, JuKernelInfo, JuHelpLink, JuCompletion, JuCompletionItem, JuInspection, JuIsComplete};
+pub use balance::JuBalanceChecker;
+pub use history::{JuHistoryEntry, JuHistoryQuery};
+pub use comm::{JuComm, JuCommHandler, JuCommTarget};
+pub use context::JuExecContext;
+pub use kernelspec::{InterruptMode, JuKernelSpec};
+pub use debugger::{JuDapResult, JuDebugger};
+pub use digester::{JuSigner, register_signature_scheme};
 
 #[derive(Debug, thiserror::Error)]
 pub enum JuError {
+    #[error(transparent)]
+    IoError(#[from] std::io::Error),
+
     #[error(transparent)]
     Utf8Error(#[from] std::str::Utf8Error),
 
@@ -34,11 +68,23 @@ pub enum JuError {
     #[error("Malformed Jupyter Message: {0}")]
     MalformedMessage(String),
 
+    #[error("Jupyter Message too large: {0}")]
+    MessageTooLarge(String),
+
+    #[error("Invalid message signature: {0:?}")]
+    InvalidSignature(String),
+
+    #[error("Unsupported transport: {0}")]
+    UnsupportedTransport(String),
+
     #[error("Unknown Digest: {0}")]
     UnknownDigest(String),
 
-    #[error("execute_request has no code: {0}")]
-    NoCode(String),
+    #[error("The running request does not allow stdin")]
+    StdinNotAllowed,
+
+    #[error("Interrupted")]
+    Interrupted,
 
     #[error("Juker encountered an error: {0}")]
     GeneralJukerError(String),
@@ -50,8 +96,6 @@ pub(crate) const DELIMITER: &[u8] = b"<IDS|MSG>";
 
 #[cfg(test)]
 mod tests {
-    use super::*;
-
     #[test]
     fn it_works() {
     }
@@ -1,7 +1,8 @@
 use anyhow::Result;
 use clap::Parser;
 use juker::{
-    ConnectionInfo, JuHelpLink, JuKernel, JuKernelInfo,
+    ConnectionInfo, InterruptMode, JuBalanceChecker, JuExecContext, JuHelpLink, JuIsComplete, JuKernel, JuKernelInfo, JuKernelSpec, JuServerConfig, JuServerHandle, ProtocolVersion,
+    jupyter_runtime_dir,
     message::{EvalResult, EvalValue},
     server::JuServer,
 };
@@ -17,8 +18,33 @@ pub struct JupyterApplication {
     /// Sets a custom config file
     #[arg(short, long, value_name = "FILE")]
     config: Option<PathBuf>,
+    /// Connection file written by Jupyter. Without one, a new connection file
+    /// is generated in the Jupyter runtime directory.
     #[arg(short = 'C', long)]
-    connection_file: PathBuf,
+    connection_file: Option<PathBuf>,
+    /// Transport for a generated connection file: tcp or ipc
+    #[arg(long, default_value = "tcp")]
+    transport: String,
+    /// Read the connection key from this file instead of the connection file
+    #[arg(long, value_name = "FILE")]
+    key_file: Option<PathBuf>,
+    /// Keep execution history in this file across restarts
+    #[arg(long, value_name = "FILE")]
+    history_file: Option<PathBuf>,
+    /// Mask code and outputs in message logs
+    #[arg(long)]
+    redact_logs: bool,
+    /// Jupyter messaging protocol version to speak
+    #[arg(long, value_name = "VERSION", default_value_t = ProtocolVersion::default())]
+    protocol_version: ProtocolVersion,
+    /// How Jupyter interrupts this kernel: message (interrupt_request) or
+    /// signal (SIGINT)
+    #[arg(long, value_name = "MODE", default_value_t = InterruptMode::default())]
+    interrupt_mode: InterruptMode,
+    /// Install a kernelspec with this name that launches this binary, then
+    /// exit
+    #[arg(long, value_name = "NAME")]
+    install: Option<String>,
     /// Turn debugging information on
     #[arg(short, long, action = clap::ArgAction::Count)]
     debug: u8,
@@ -26,6 +52,9 @@ pub struct JupyterApplication {
     // command: JupyterCommands,
 }
 
+/// Environment variable that overrides the key from the connection file.
+const KEY_ENV_VAR: &str = "JUKER_KEY";
+
 // #[derive(Subcommand)]
 // enum JupyterCommands {
 //     Open(Box<OpenAction>),
@@ -42,29 +71,50 @@ impl JupyterApplication {
         debug!("Debug log example");
         trace!("Trace log example");
 
-        let f = File::open(&self.connection_file)?;
-        info!("Opened connection file: {:?}", f);
+        if let Some(name) = &self.install {
+            let mode = self.interrupt_mode.to_string();
+            let spec = JuKernelSpec::for_current_exe("Juker", "nothing", ["--interrupt-mode", mode.as_str()])?
+                .with_interrupt_mode(self.interrupt_mode);
+            let path = spec.install(name)?;
+            eprintln!("Installed kernelspec {name} in {}", path.display());
+            return Ok(());
+        }
 
-        let ci: ConnectionInfo = serde_json::from_reader(f)?;
+        let mut config = JuServerConfig {
+            protocol_version: self.protocol_version,
+            redact_message_logs: self.redact_logs,
+            history_file: self.history_file.clone(),
+            interrupt_on_sigint: self.interrupt_mode == InterruptMode::Signal,
+            shutdown_on_sigterm: true,
+            ..Default::default()
+        };
+
+        let mut announce = None;
+        let mut ci = match &self.connection_file {
+            Some(path) => {
+                let f = File::open(path)?;
+                info!("Opened connection file: {:?}", f);
+                serde_json::from_reader(f)?
+            }
+            None => {
+                let path = jupyter_runtime_dir().join(format!("kernel-juker-{}.json", std::process::id()));
+                announce = Some(path.clone());
+                config.connection_file = Some(path);
+                ConnectionInfo::builder()
+                    .kernel_name("juker")
+                    .transport(&self.transport)
+                    .build()?
+            }
+        };
+        ci = ci.with_key_from_env(KEY_ENV_VAR);
+        if let Some(key_file) = &self.key_file {
+            ci = ci.with_key_from_file(key_file)?;
+        }
         info!("Connection file content: {:?}", ci);
 
-        loop {
-            let eva = Eva {};
-            let res = JuServer::start(&ci, eva).await;
-
-            match &res {
-                Ok(true) => {
-                    info!("Server exited and requested restart, restarting.");
-                    continue;
-                }
-                Ok(false) => {
-                    info!("Server exited successfully.");
-                }
-                Err(e) => {
-                    error!("Server error: {:?}", e);
-                }
-            }
-            break;
+        match JuServer::start_with_config(&ci, Eva { announce }, config).await {
+            Ok(()) => info!("Server exited successfully."),
+            Err(e) => error!("Server error: {:?}", e),
         }
 
         Ok(())
@@ -122,9 +172,22 @@ async fn main() -> Result<()> {
     res
 }
 
-struct Eva;
+struct Eva {
+    /// Generated connection file to point the user at once the server has
+    /// written it.
+    announce: Option<PathBuf>,
+}
 
 impl JuKernel for Eva {
+    fn attach(&mut self, _server: JuServerHandle) {
+        if let Some(path) = self.announce.take() {
+            eprintln!(
+                "To connect another client to this kernel, use:\n    --existing {}",
+                path.display()
+            );
+        }
+    }
+
     fn kernel_info(&self) -> JuKernelInfo {
         JuKernelInfo {
             name: "testing".to_string(),
@@ -139,12 +202,17 @@ impl JuKernel for Eva {
         }
     }
 
-    async fn eval_code(&mut self, code: String) -> EvalResult {
+    async fn eval_code(&mut self, code: String, ctx: JuExecContext) -> EvalResult {
+        if let Some(text) = code.strip_prefix("print ") {
+            ctx.stdout(format!("{text}\n"));
+        }
+
         if code.starts_with("err") {
             EvalResult::Error {
-                ename: json!("Error"),
-                evalue: json!("An error occurred during code execution"),
-                traceback: vec![json!("Traceback (most recent call last):"), json!("  ...")],
+                ename: "Error".to_string(),
+                evalue: "An error occurred during code execution".to_string(),
+                traceback: vec!["Traceback (most recent call last):".to_string(), "  ...".to_string()],
+                payload: Vec::new(),
             }
         } else {
             EvalResult::Success {
@@ -153,8 +221,14 @@ impl JuKernel for Eva {
                         "text/plain": format!("Executed code: {}", code),
                     }),
                     metadata: json!({}),
+                    buffers: Vec::new(),
                 }],
+                payload: Vec::new(),
             }
         }
     }
+
+    async fn is_complete(&mut self, code: String) -> JuIsComplete {
+        JuBalanceChecker::new().check(&code)
+    }
 }
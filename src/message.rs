@@ -1,6 +1,11 @@
 use std::ops::ControlFlow;
 
-use crate::{DELIMITER, JuError, JuResult, digester::Digester};
+use crate::{
+    DELIMITER, JuError, JuResult,
+    config::JuServerConfig,
+    digester::Digester,
+    protocol::{Header, JuContent, Payload},
+};
 use bytes::Bytes;
 use serde_json::Value;
 use zeromq::ZmqMessage;
@@ -8,17 +13,32 @@ use zeromq::ZmqMessage;
 pub enum EvalResult {
     Success {
         results: Vec<EvalValue>,
+        payload: Vec<Payload>,
     },
     Error {
-        ename: Value,
-        evalue: Value,
-        traceback: Vec<Value>,
+        ename: String,
+        evalue: String,
+        traceback: Vec<String>,
+        payload: Vec<Payload>,
     },
 }
 
+impl EvalResult {
+    /// The error an interrupted execution ends with.
+    pub fn interrupted() -> Self {
+        EvalResult::Error {
+            ename: "KeyboardInterrupt".to_string(),
+            evalue: String::new(),
+            traceback: vec!["KeyboardInterrupt".to_string()],
+            payload: Vec::new(),
+        }
+    }
+}
+
 pub struct EvalValue {
     pub data: Value,
     pub metadata: Value,
+    pub buffers: Vec<Bytes>,
 }
 
 pub enum MsgSource<T> {
@@ -26,35 +46,63 @@ pub enum MsgSource<T> {
     Control(T),
     Execution {
         eval_result: EvalResult,
-        original_msg: JuMessage,
+        original_msg: Box<JuMessage>,
     },
 }
 
 pub struct JuMessage {
     pub zmq_ids: Vec<Bytes>,
-    pub header: Value,
-    pub parent_header: Value,
+    pub header: Header,
+    /// `None` when the message has no parent, sent as `{}` on the wire.
+    pub parent_header: Option<Header>,
     pub metadata: Value,
     pub content: Value,
+    /// Raw binary frames sent after `content`. They are not covered by the
+    /// message signature.
+    pub buffers: Vec<Bytes>,
 }
 
 impl JuMessage {
-    pub fn with_content(mut self, content: Value) -> Self {
-        self.content = content;
+    pub fn msg_type(&self) -> &str {
+        &self.header.msg_type
+    }
+
+    /// Decodes the content according to the header's `msg_type`.
+    pub fn typed_content(&self) -> JuResult<JuContent> {
+        JuContent::parse(&self.header.msg_type, self.content.clone())
+    }
+
+    pub fn with_content(mut self, content: impl Into<Value>) -> Self {
+        self.content = content.into();
         self
     }
 
-    pub(crate) fn to_zmq_message(self, digester: &Digester) -> ZmqMessage {
+    /// Sets a typed content from [`crate::protocol`], failing if it does not
+    /// serialize to JSON.
+    pub fn try_with_content<C: TryInto<Value, Error = JuError>>(mut self, content: C) -> JuResult<Self> {
+        self.content = content.try_into()?;
+        Ok(self)
+    }
+
+    pub fn with_buffers(mut self, buffers: Vec<Bytes>) -> Self {
+        self.buffers = buffers;
+        self
+    }
+
+    pub(crate) fn to_zmq_message(&self, digester: &Digester) -> JuResult<ZmqMessage> {
         let mut msg: ZmqMessage = Bytes::from_static(DELIMITER).into();
 
-        for id in self.zmq_ids.into_iter().rev() {
-            msg.push_front(id);
+        for id in self.zmq_ids.iter().rev() {
+            msg.push_front(id.clone());
         }
 
-        let header = Bytes::from(serde_json::to_vec(&self.header).unwrap());
-        let parent_header = Bytes::from(serde_json::to_vec(&self.parent_header).unwrap());
-        let metadata = Bytes::from(serde_json::to_vec(&self.metadata).unwrap());
-        let content = Bytes::from(serde_json::to_vec(&self.content).unwrap());
+        let header = Bytes::from(serde_json::to_vec(&self.header)?);
+        let parent_header = match &self.parent_header {
+            Some(parent) => Bytes::from(serde_json::to_vec(parent)?),
+            None => Bytes::from_static(b"{}"),
+        };
+        let metadata = Bytes::from(serde_json::to_vec(&self.metadata)?);
+        let content = Bytes::from(serde_json::to_vec(&self.content)?);
 
         let hmac = digester.digest(&header, &parent_header, &metadata, &content);
         msg.push_back(hmac);
@@ -63,12 +111,22 @@ impl JuMessage {
         msg.push_back(parent_header);
         msg.push_back(metadata);
         msg.push_back(content);
-        msg
+
+        for buffer in &self.buffers {
+            msg.push_back(buffer.clone());
+        }
+        Ok(msg)
     }
 }
 
-impl std::fmt::Debug for JuMessage {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl JuMessage {
+    /// Debug view for logging. With `redact` set, code, outputs and input
+    /// replies in the content are masked.
+    pub(crate) fn log_view(&self, redact: bool) -> JuMessageLogView<'_> {
+        JuMessageLogView { msg: self, redact }
+    }
+
+    fn fmt_with_content(&self, f: &mut std::fmt::Formatter<'_>, content: &Value) -> std::fmt::Result {
         write!(f, "JupyterMessage {{ zmq_ids: [")?;
 
         let mut first = true;
@@ -83,8 +141,12 @@ impl std::fmt::Debug for JuMessage {
 
         write!(
             f,
-            "], header: {}, parent_header: {}, metadata: {}, content: {} }}",
-            self.header, self.parent_header, self.metadata, self.content
+            "], header: {}, parent_header: {}, metadata: {}, content: {}, buffers: {:?} }}",
+            serde_json::to_string(&self.header).unwrap_or_default(),
+            serde_json::to_string(&self.parent_header).unwrap_or_default(),
+            self.metadata,
+            content,
+            self.buffers.iter().map(Bytes::len).collect::<Vec<_>>()
         )
     }
 }
@@ -92,7 +154,80 @@ impl std::fmt::Debug for JuMessage {
 impl TryFrom<ZmqMessage> for JuMessage {
     type Error = JuError;
 
+    /// Decodes a message WITHOUT checking its signature, within the default
+    /// size limits. The server's sockets verify what they receive; use this
+    /// only for messages that are already trusted.
     fn try_from(msg: ZmqMessage) -> JuResult<Self> {
+        let unsigned = Digester::from_scheme("", b"")?;
+        Self::from_zmq_message(msg, &unsigned, &JuServerConfig::default())
+    }
+}
+
+impl std::fmt::Debug for JuMessage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.fmt_with_content(f, &self.content)
+    }
+}
+
+/// Content fields that may carry user code or computed results.
+const REDACTED_FIELDS: &[&str] = &[
+    "code",
+    "data",
+    "text",
+    "traceback",
+    "evalue",
+    "history",
+    "payload",
+    "user_expressions",
+    "value",
+];
+
+/// Fields of the DAP messages carried by `debug_request`, `debug_reply` and
+/// `debug_event`, which hold source code, variables and evaluation results.
+const REDACTED_DEBUG_FIELDS: &[&str] = &["arguments", "body"];
+
+pub(crate) struct JuMessageLogView<'a> {
+    msg: &'a JuMessage,
+    redact: bool,
+}
+
+impl std::fmt::Debug for JuMessageLogView<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if !self.redact {
+            return self.msg.fmt_with_content(f, &self.msg.content);
+        }
+
+        let mut content = self.msg.content.clone();
+        if let Some(map) = content.as_object_mut() {
+            let debug = matches!(
+                self.msg.msg_type(),
+                "debug_request" | "debug_reply" | "debug_event"
+            );
+            let extra = if debug { REDACTED_DEBUG_FIELDS } else { &[] };
+            for field in REDACTED_FIELDS.iter().chain(extra) {
+                if let Some(v) = map.get_mut(*field) {
+                    *v = Value::String("<redacted>".into());
+                }
+            }
+        }
+        self.msg.fmt_with_content(f, &content)
+    }
+}
+
+impl JuMessage {
+    /// Parses and authenticates a multipart message received from a socket.
+    ///
+    /// Size limits from `config` are enforced first, then the signature frame
+    /// is checked against the digest of the raw header, parent header,
+    /// metadata and content frames before any of them is decoded, so
+    /// oversized or tampered messages never reach the JSON parser.
+    pub(crate) fn from_zmq_message(
+        msg: ZmqMessage,
+        digester: &Digester,
+        config: &JuServerConfig,
+    ) -> JuResult<Self> {
+        check_size(&msg, config)?;
+
         let mut it = msg.iter();
 
         let zmq_ids = it
@@ -109,37 +244,365 @@ impl TryFrom<ZmqMessage> for JuMessage {
 
         let sig = it
             .next()
-            .ok_or(JuError::MalformedMessage("no signature".into()))?
-            .to_vec();
+            .ok_or(JuError::MalformedMessage("no signature".into()))?;
+        let header = it
+            .next()
+            .ok_or(JuError::MalformedMessage("no header".into()))?;
+        let parent_header = it
+            .next()
+            .ok_or(JuError::MalformedMessage("no parent header".into()))?;
+        let metadata = it
+            .next()
+            .ok_or(JuError::MalformedMessage("no metadata".into()))?;
+        let content = it
+            .next()
+            .ok_or(JuError::MalformedMessage("no content".into()))?;
 
-        // TODO: verify signature
+        if !digester.verify(sig, header, parent_header, metadata, content) {
+            return Err(JuError::InvalidSignature(
+                String::from_utf8_lossy(sig).into_owned(),
+            ));
+        }
 
-        let header: Value = serde_json::from_slice(
-            it.next()
-                .ok_or(JuError::MalformedMessage("no header".into()))?,
-        )?;
+        let header = parse_object("header", header)?;
+        // Replay protection goes by the date, which serde would default to
+        // now, so a signed message must carry its own.
+        if digester.is_signed() && header.get("date").is_none() {
+            return Err(JuError::MalformedMessage("signed header has no date".into()));
+        }
 
-        let parent_header: Value = serde_json::from_slice(
-            it.next()
-                .ok_or(JuError::MalformedMessage("no parent header".into()))?,
-        )?;
+        Ok(JuMessage {
+            zmq_ids,
+            header: header_from_value("header", header)?,
+            parent_header: parse_parent_header(parent_header)?,
+            metadata: parse_object("metadata", metadata)?,
+            content: parse_object("content", content)?,
+            buffers: it.cloned().collect(),
+        })
+    }
+}
+
+fn check_size(msg: &ZmqMessage, config: &JuServerConfig) -> JuResult<()> {
+    let mut total = 0usize;
+    for frame in msg.iter() {
+        if frame.len() > config.max_frame_size {
+            return Err(JuError::MessageTooLarge(format!(
+                "frame of {} bytes exceeds the {} byte limit",
+                frame.len(),
+                config.max_frame_size
+            )));
+        }
+        total += frame.len();
+    }
+
+    if total > config.max_message_size {
+        return Err(JuError::MessageTooLarge(format!(
+            "message of {} bytes exceeds the {} byte limit",
+            total, config.max_message_size
+        )));
+    }
+    Ok(())
+}
 
-        let metadata: Value = serde_json::from_slice(
-            it.next()
-                .ok_or(JuError::MalformedMessage("no metadata".into()))?,
-        )?;
+fn parse_header(what: &str, frame: &[u8]) -> JuResult<Header> {
+    header_from_value(what, parse_object(what, frame)?)
+}
 
-        let content: Value = serde_json::from_slice(
-            it.next()
-                .ok_or(JuError::MalformedMessage("no content".into()))?,
-        )?;
+fn header_from_value(what: &str, value: Value) -> JuResult<Header> {
+    serde_json::from_value(value)
+        .map_err(|e| JuError::MalformedMessage(format!("invalid {what}: {e}")))
+}
 
-        Ok(JuMessage {
-            zmq_ids,
-            header,
-            parent_header,
-            metadata,
-            content,
+fn parse_parent_header(frame: &[u8]) -> JuResult<Option<Header>> {
+    let value = parse_object("parent header", frame)?;
+    if value.as_object().is_some_and(|m| m.is_empty()) {
+        return Ok(None);
+    }
+    parse_header("parent header", frame).map(Some)
+}
+
+fn parse_object(what: &str, frame: &[u8]) -> JuResult<Value> {
+    let value: Value = serde_json::from_slice(frame)?;
+    if !value.is_object() {
+        return Err(JuError::MalformedMessage(format!(
+            "{what} is not a JSON object"
+        )));
+    }
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use serde_json::json;
+
+    fn signed_digester() -> Digester {
+        Digester::from_scheme("hmac-sha256", b"secret").unwrap()
+    }
+
+    fn header(msg_id: &str, msg_type: &str) -> Header {
+        serde_json::from_value(json!({
+            "msg_id": msg_id,
+            "session": "client",
+            "msg_type": msg_type,
+        }))
+        .unwrap()
+    }
+
+    fn sample_message() -> JuMessage {
+        JuMessage {
+            zmq_ids: vec![Bytes::from_static(b"client")],
+            header: header("1", "execute_request"),
+            parent_header: None,
+            metadata: json!({}),
+            content: json!({"code": "1 + 1"}),
+            buffers: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn signed_message_round_trips() {
+        let digester = signed_digester();
+        let zmsg = sample_message().to_zmq_message(&digester).unwrap();
+
+        let msg = JuMessage::from_zmq_message(zmsg, &digester, &JuServerConfig::default()).unwrap();
+        assert_eq!(msg.zmq_ids, vec![Bytes::from_static(b"client")]);
+        assert_eq!(msg.content["code"], "1 + 1");
+    }
+
+    #[test]
+    fn tampered_content_is_rejected() {
+        let digester = signed_digester();
+        let zmsg = sample_message().to_zmq_message(&digester).unwrap();
+
+        let mut frames = zmsg.into_vec();
+        let last = frames.len() - 1;
+        frames[last] = Bytes::from_static(br#"{"code": "rm -rf /"}"#);
+        let zmsg = ZmqMessage::try_from(frames).unwrap();
+
+        let res = JuMessage::from_zmq_message(zmsg, &digester, &JuServerConfig::default());
+        assert!(matches!(res, Err(JuError::InvalidSignature(_))));
+    }
+
+    #[test]
+    fn signed_header_without_date_is_rejected() {
+        let digester = signed_digester();
+        let header = Bytes::from_static(br#"{"msg_id": "1", "session": "client", "msg_type": "execute_request"}"#);
+        let empty = Bytes::from_static(b"{}");
+        let content = Bytes::from_static(br#"{"code": "1 + 1"}"#);
+        let sig = digester.digest(&header, &empty, &empty, &content);
+        let frames = vec![Bytes::from_static(DELIMITER), sig, header, empty.clone(), empty, content];
+        let zmsg = ZmqMessage::try_from(frames).unwrap();
+
+        let res = JuMessage::from_zmq_message(zmsg.clone(), &digester, &JuServerConfig::default());
+        assert!(matches!(res, Err(JuError::MalformedMessage(_))));
+
+        let unsigned = Digester::from_scheme("", b"").unwrap();
+        assert!(JuMessage::from_zmq_message(zmsg, &unsigned, &JuServerConfig::default()).is_ok());
+    }
+
+    #[test]
+    fn try_from_decodes_without_verifying() {
+        let zmsg = sample_message().to_zmq_message(&signed_digester()).unwrap();
+
+        let msg = JuMessage::try_from(zmsg).unwrap();
+        assert_eq!(msg.header.msg_id, "1");
+        assert_eq!(msg.content["code"], "1 + 1");
+    }
+
+    #[test]
+    fn wrong_key_is_rejected() {
+        let zmsg = sample_message().to_zmq_message(&signed_digester()).unwrap();
+        let other = Digester::from_scheme("hmac-sha256", b"other").unwrap();
+
+        let res = JuMessage::from_zmq_message(zmsg, &other, &JuServerConfig::default());
+        assert!(matches!(res, Err(JuError::InvalidSignature(_))));
+    }
+
+    #[test]
+    fn buffers_follow_content_and_are_not_signed() {
+        let digester = signed_digester();
+        let msg = sample_message().with_buffers(vec![
+            Bytes::from_static(&[0, 1, 2]),
+            Bytes::from_static(b"second"),
+        ]);
+        let zmsg = msg.to_zmq_message(&digester).unwrap();
+
+        let mut frames = zmsg.into_vec();
+        assert_eq!(frames.len(), 9);
+        let last = frames.len() - 1;
+        frames[last] = Bytes::from_static(b"changed");
+        let zmsg = ZmqMessage::try_from(frames).unwrap();
+
+        let parsed = JuMessage::from_zmq_message(zmsg, &digester, &JuServerConfig::default()).unwrap();
+        assert_eq!(
+            parsed.buffers,
+            vec![Bytes::from_static(&[0, 1, 2]), Bytes::from_static(b"changed")]
+        );
+    }
+
+    #[test]
+    fn redacted_log_view_hides_code() {
+        let msg = sample_message();
+        assert!(format!("{:?}", msg.log_view(false)).contains("1 + 1"));
+
+        let redacted = format!("{:?}", msg.log_view(true));
+        assert!(!redacted.contains("1 + 1"));
+        assert!(redacted.contains("execute_request"));
+    }
+
+    #[test]
+    fn redacted_log_view_hides_history() {
+        let mut msg = sample_message().with_content(json!({
+            "status": "ok",
+            "history": [[0, 1, "secret = 42"]],
+        }));
+        msg.header = header("2", "history_reply");
+
+        let redacted = format!("{:?}", msg.log_view(true));
+        assert!(!redacted.contains("secret = 42"));
+        assert!(redacted.contains("history_reply"));
+    }
+
+    #[test]
+    fn redacted_log_view_hides_debug_arguments_and_bodies() {
+        let mut msg = sample_message().with_content(json!({
+            "seq": 3,
+            "type": "request",
+            "command": "dumpCell",
+            "arguments": { "code": "token = 'hunter2'" },
+        }));
+        msg.header = header("3", "debug_request");
+        let redacted = format!("{:?}", msg.log_view(true));
+        assert!(!redacted.contains("hunter2"));
+        assert!(redacted.contains("dumpCell"));
+
+        let mut msg = sample_message().with_content(json!({
+            "seq": 4,
+            "type": "event",
+            "event": "output",
+            "body": { "output": "hunter2" },
+        }));
+        msg.header = header("4", "debug_event");
+        assert!(!format!("{:?}", msg.log_view(true)).contains("hunter2"));
+    }
+
+    #[test]
+    fn oversized_frame_is_rejected() {
+        let digester = signed_digester();
+        let msg = sample_message().with_content(json!({"code": "x".repeat(1024)}));
+        let zmsg = msg.to_zmq_message(&digester).unwrap();
+
+        let config = JuServerConfig {
+            max_frame_size: 512,
+            ..Default::default()
+        };
+        let res = JuMessage::from_zmq_message(zmsg, &digester, &config);
+        assert!(matches!(res, Err(JuError::MessageTooLarge(_))));
+    }
+
+    #[test]
+    fn oversized_message_is_rejected() {
+        let digester = signed_digester();
+        let zmsg = sample_message().to_zmq_message(&digester).unwrap();
+
+        let config = JuServerConfig {
+            max_message_size: 64,
+            ..Default::default()
+        };
+        let res = JuMessage::from_zmq_message(zmsg, &digester, &config);
+        assert!(matches!(res, Err(JuError::MessageTooLarge(_))));
+    }
+
+    #[test]
+    fn non_object_content_is_rejected() {
+        let digester = signed_digester();
+        let zmsg = sample_message()
+            .with_content(json!(["not", "an", "object"]))
+            .to_zmq_message(&digester)
+            .unwrap();
+
+        let res = JuMessage::from_zmq_message(zmsg, &digester, &JuServerConfig::default());
+        assert!(matches!(res, Err(JuError::MalformedMessage(_))));
+    }
+
+    fn json_value() -> impl Strategy<Value = Value> {
+        let leaf = prop_oneof![
+            Just(Value::Null),
+            any::<bool>().prop_map(Value::from),
+            any::<i64>().prop_map(Value::from),
+            ".*".prop_map(Value::from),
+        ];
+        leaf.prop_recursive(3, 32, 4, |inner| {
+            prop_oneof![
+                prop::collection::vec(inner.clone(), 0..4).prop_map(Value::from),
+                prop::collection::btree_map(".*", inner, 0..4)
+                    .prop_map(|m| Value::Object(m.into_iter().collect())),
+            ]
         })
     }
+
+    fn json_object() -> impl Strategy<Value = Value> {
+        prop::collection::btree_map(".*", json_value(), 0..4)
+            .prop_map(|m| Value::Object(m.into_iter().collect()))
+    }
+
+    fn header_strategy() -> impl Strategy<Value = Header> {
+        (".*", ".*", ".*", 0i64..4_000_000_000_000_000, ".*", ".*").prop_map(
+            |(msg_id, session, username, micros, msg_type, version)| Header {
+                msg_id,
+                session,
+                username,
+                date: chrono::DateTime::from_timestamp_micros(micros).unwrap(),
+                msg_type,
+                version,
+                extra: Default::default(),
+            },
+        )
+    }
+
+    fn zmq_id() -> impl Strategy<Value = Bytes> {
+        prop::collection::vec(any::<u8>(), 1..16)
+            .prop_filter("ids must not look like the delimiter", |id| id != DELIMITER)
+            .prop_map(Bytes::from)
+    }
+
+    proptest! {
+        #[test]
+        fn round_trips_random_messages(
+            zmq_ids in prop::collection::vec(zmq_id(), 0..3),
+            header in header_strategy(),
+            parent_header in prop::option::of(header_strategy()),
+            metadata in json_object(),
+            content in json_object(),
+            buffers in prop::collection::vec(prop::collection::vec(any::<u8>(), 0..64).prop_map(Bytes::from), 0..3),
+        ) {
+            let digester = signed_digester();
+            let msg = JuMessage { zmq_ids, header, parent_header, metadata, content, buffers };
+
+            let zmsg = msg.to_zmq_message(&digester).unwrap();
+            let parsed = JuMessage::from_zmq_message(zmsg, &digester, &JuServerConfig::default()).unwrap();
+
+            prop_assert_eq!(parsed.zmq_ids, msg.zmq_ids);
+            prop_assert_eq!(parsed.header, msg.header);
+            prop_assert_eq!(parsed.parent_header, msg.parent_header);
+            prop_assert_eq!(parsed.metadata, msg.metadata);
+            prop_assert_eq!(parsed.content, msg.content);
+            prop_assert_eq!(parsed.buffers, msg.buffers);
+        }
+
+        #[test]
+        fn random_frames_never_panic(
+            frames in prop::collection::vec(prop::collection::vec(any::<u8>(), 0..64), 1..10),
+            delimiter_at in any::<prop::sample::Index>(),
+        ) {
+            let mut frames: Vec<Bytes> = frames.into_iter().map(Bytes::from).collect();
+            let idx = delimiter_at.index(frames.len());
+            frames[idx] = Bytes::from_static(DELIMITER);
+
+            let zmsg = ZmqMessage::try_from(frames).unwrap();
+            let _ = JuMessage::from_zmq_message(zmsg, &Digester::from_scheme("", b"").unwrap(), &JuServerConfig::default());
+        }
+    }
 }
@@ -0,0 +1,605 @@
+//! Typed Jupyter messaging protocol (v5) headers and message contents.
+//!
+//! [`JuMessage`](crate::JuMessage) keeps its content as raw JSON; use
+//! [`JuMessage::typed_content`](crate::JuMessage::typed_content) to decode it
+//! into a [`JuContent`] and build outgoing contents from the structs here.
+
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, NaiveDateTime, SecondsFormat, Utc};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::{Map, Value};
+
+use crate::{JuError, JuResult};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Header {
+    pub msg_id: String,
+    pub session: String,
+    #[serde(default)]
+    pub username: String,
+    #[serde(
+        default = "Utc::now",
+        serialize_with = "serialize_date",
+        deserialize_with = "deserialize_date"
+    )]
+    pub date: DateTime<Utc>,
+    pub msg_type: String,
+    #[serde(default)]
+    pub version: String,
+    /// Header fields not covered above, kept so that parent headers are
+    /// echoed back unchanged.
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+fn serialize_date<S: Serializer>(date: &DateTime<Utc>, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&date.to_rfc3339_opts(SecondsFormat::Micros, true))
+}
+
+/// Accepts RFC 3339 dates as well as the naive ISO 8601 form older clients
+/// send, which is taken to be UTC.
+fn deserialize_date<'de, D: Deserializer<'de>>(deserializer: D) -> Result<DateTime<Utc>, D::Error> {
+    let s = String::deserialize(deserializer)?;
+
+    if let Ok(date) = DateTime::parse_from_rfc3339(&s) {
+        return Ok(date.with_timezone(&Utc));
+    }
+
+    NaiveDateTime::parse_from_str(&s, "%Y-%m-%dT%H:%M:%S%.f")
+        .map(|date| date.and_utc())
+        .map_err(serde::de::Error::custom)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ReplyStatus {
+    Ok,
+    Error,
+    Aborted,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ExecutionState {
+    Starting,
+    Busy,
+    Idle,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum StreamName {
+    Stdout,
+    Stderr,
+}
+
+/// `ename`/`evalue`/`traceback`, shared by error replies and `error` messages.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ErrorInfo {
+    pub ename: String,
+    pub evalue: String,
+    pub traceback: Vec<String>,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn empty_object() -> Value {
+    Value::Object(Map::new())
+}
+
+// Shell channel.
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ExecuteRequest {
+    pub code: String,
+    #[serde(default)]
+    pub silent: bool,
+    #[serde(default = "default_true")]
+    pub store_history: bool,
+    #[serde(default)]
+    pub user_expressions: BTreeMap<String, String>,
+    #[serde(default = "default_true")]
+    pub allow_stdin: bool,
+    #[serde(default = "default_true")]
+    pub stop_on_error: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ExecuteReply {
+    pub status: ReplyStatus,
+    pub execution_count: u32,
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    pub error: Option<ErrorInfo>,
+    #[serde(default)]
+    pub payload: Vec<Value>,
+    #[serde(default)]
+    pub user_expressions: Map<String, Value>,
+}
+
+/// An action for the frontend, sent in `execute_reply.payload`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "source", rename_all = "snake_case")]
+pub enum Payload {
+    /// Shows `data`, a MIME bundle, in the pager, scrolled to line `start`.
+    Page {
+        data: Value,
+        #[serde(default)]
+        start: u32,
+    },
+    /// Puts `text` into the next input cell, or with `replace` into the one
+    /// that was run.
+    SetNextInput {
+        text: String,
+        #[serde(default)]
+        replace: bool,
+    },
+    /// Opens `filename` in an editor at `line_number`.
+    EditMagic { filename: String, line_number: u32 },
+    /// Asks the frontend to exit; with `keepkernel` the kernel stays up.
+    AskExit {
+        #[serde(default)]
+        keepkernel: bool,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct InspectRequest {
+    pub code: String,
+    pub cursor_pos: usize,
+    #[serde(default)]
+    pub detail_level: u8,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct InspectReply {
+    pub status: ReplyStatus,
+    pub found: bool,
+    pub data: Value,
+    pub metadata: Value,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CompleteRequest {
+    pub code: String,
+    pub cursor_pos: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CompleteReply {
+    pub status: ReplyStatus,
+    pub matches: Vec<String>,
+    pub cursor_start: usize,
+    pub cursor_end: usize,
+    pub metadata: Value,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum HistAccessType {
+    Range,
+    Tail,
+    Search,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct HistoryRequest {
+    #[serde(default)]
+    pub output: bool,
+    #[serde(default)]
+    pub raw: bool,
+    pub hist_access_type: HistAccessType,
+    #[serde(default)]
+    pub session: Option<i64>,
+    #[serde(default)]
+    pub start: Option<i64>,
+    #[serde(default)]
+    pub stop: Option<i64>,
+    #[serde(default)]
+    pub n: Option<usize>,
+    #[serde(default)]
+    pub pattern: Option<String>,
+    #[serde(default)]
+    pub unique: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct HistoryReply {
+    pub status: ReplyStatus,
+    pub history: Vec<Value>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct IsCompleteRequest {
+    pub code: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum IsCompleteStatus {
+    Complete,
+    Incomplete,
+    Invalid,
+    Unknown,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct IsCompleteReply {
+    pub status: IsCompleteStatus,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub indent: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct KernelInfoRequest {}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LanguageInfo {
+    pub name: String,
+    pub version: String,
+    pub mimetype: String,
+    pub file_extension: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct HelpLink {
+    pub text: String,
+    pub url: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct KernelInfoReply {
+    pub status: ReplyStatus,
+    pub protocol_version: String,
+    pub implementation: String,
+    pub implementation_version: String,
+    pub language_info: LanguageInfo,
+    pub banner: String,
+    pub help_links: Vec<HelpLink>,
+    /// Since protocol 5.3.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub debugger: Option<bool>,
+    /// Since protocol 5.5.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub supported_features: Option<Vec<String>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct CommInfoRequest {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target_name: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CommInfo {
+    pub target_name: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CommInfoReply {
+    pub status: ReplyStatus,
+    pub comms: BTreeMap<String, CommInfo>,
+}
+
+// Comms, sent on shell by frontends and on IOPub by kernels.
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CommOpen {
+    pub comm_id: String,
+    pub target_name: String,
+    #[serde(default = "empty_object")]
+    pub data: Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target_module: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CommMsg {
+    pub comm_id: String,
+    #[serde(default = "empty_object")]
+    pub data: Value,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CommClose {
+    pub comm_id: String,
+    #[serde(default = "empty_object")]
+    pub data: Value,
+}
+
+// Control channel.
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ShutdownRequest {
+    #[serde(default)]
+    pub restart: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ShutdownReply {
+    pub status: ReplyStatus,
+    pub restart: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct InterruptRequest {}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct InterruptReply {
+    pub status: ReplyStatus,
+}
+
+/// A Debug Adapter Protocol request; the content is the DAP message itself.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(transparent)]
+pub struct DebugRequest(pub Value);
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(transparent)]
+pub struct DebugReply(pub Value);
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(transparent)]
+pub struct DebugEvent(pub Value);
+
+// IOPub channel.
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Status {
+    pub execution_state: ExecutionState,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Stream {
+    pub name: StreamName,
+    pub text: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DisplayData {
+    pub data: Value,
+    #[serde(default = "empty_object")]
+    pub metadata: Value,
+    #[serde(default = "empty_object")]
+    pub transient: Value,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct UpdateDisplayData {
+    pub data: Value,
+    #[serde(default = "empty_object")]
+    pub metadata: Value,
+    pub transient: Value,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ExecuteInput {
+    pub code: String,
+    pub execution_count: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ExecuteResult {
+    pub execution_count: u32,
+    pub data: Value,
+    #[serde(default = "empty_object")]
+    pub metadata: Value,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(transparent)]
+pub struct ErrorContent(pub ErrorInfo);
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ClearOutput {
+    #[serde(default)]
+    pub wait: bool,
+}
+
+// Stdin channel.
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct InputRequest {
+    pub prompt: String,
+    #[serde(default)]
+    pub password: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct InputReply {
+    pub value: String,
+}
+
+macro_rules! contents {
+    ($($variant:ident($ty:ty) = $msg_type:literal,)*) => {
+        /// Message content decoded according to the header's `msg_type`.
+        ///
+        /// Types juker does not know about are kept as raw JSON in
+        /// [`JuContent::Unknown`].
+        #[derive(Debug, Clone, PartialEq)]
+        pub enum JuContent {
+            $($variant($ty),)*
+            Unknown {
+                msg_type: String,
+                content: Value,
+            },
+        }
+
+        impl JuContent {
+            pub fn parse(msg_type: &str, content: Value) -> JuResult<Self> {
+                Ok(match msg_type {
+                    $($msg_type => Self::$variant(serde_json::from_value(content)?),)*
+                    _ => Self::Unknown {
+                        msg_type: msg_type.to_string(),
+                        content,
+                    },
+                })
+            }
+
+            pub fn msg_type(&self) -> &str {
+                match self {
+                    $(Self::$variant(_) => $msg_type,)*
+                    Self::Unknown { msg_type, .. } => msg_type,
+                }
+            }
+        }
+
+        $(
+            impl TryFrom<$ty> for Value {
+                type Error = JuError;
+
+                fn try_from(content: $ty) -> JuResult<Value> {
+                    Ok(serde_json::to_value(content)?)
+                }
+            }
+        )*
+    };
+}
+
+contents! {
+    ExecuteRequest(ExecuteRequest) = "execute_request",
+    ExecuteReply(ExecuteReply) = "execute_reply",
+    InspectRequest(InspectRequest) = "inspect_request",
+    InspectReply(InspectReply) = "inspect_reply",
+    CompleteRequest(CompleteRequest) = "complete_request",
+    CompleteReply(CompleteReply) = "complete_reply",
+    HistoryRequest(HistoryRequest) = "history_request",
+    HistoryReply(HistoryReply) = "history_reply",
+    IsCompleteRequest(IsCompleteRequest) = "is_complete_request",
+    IsCompleteReply(IsCompleteReply) = "is_complete_reply",
+    KernelInfoRequest(KernelInfoRequest) = "kernel_info_request",
+    KernelInfoReply(KernelInfoReply) = "kernel_info_reply",
+    CommInfoRequest(CommInfoRequest) = "comm_info_request",
+    CommInfoReply(CommInfoReply) = "comm_info_reply",
+    CommOpen(CommOpen) = "comm_open",
+    CommMsg(CommMsg) = "comm_msg",
+    CommClose(CommClose) = "comm_close",
+    ShutdownRequest(ShutdownRequest) = "shutdown_request",
+    ShutdownReply(ShutdownReply) = "shutdown_reply",
+    InterruptRequest(InterruptRequest) = "interrupt_request",
+    InterruptReply(InterruptReply) = "interrupt_reply",
+    DebugRequest(DebugRequest) = "debug_request",
+    DebugReply(DebugReply) = "debug_reply",
+    DebugEvent(DebugEvent) = "debug_event",
+    Status(Status) = "status",
+    Stream(Stream) = "stream",
+    DisplayData(DisplayData) = "display_data",
+    UpdateDisplayData(UpdateDisplayData) = "update_display_data",
+    ExecuteInput(ExecuteInput) = "execute_input",
+    ExecuteResult(ExecuteResult) = "execute_result",
+    Error(ErrorContent) = "error",
+    ClearOutput(ClearOutput) = "clear_output",
+    InputRequest(InputRequest) = "input_request",
+    InputReply(InputReply) = "input_reply",
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn header_accepts_naive_dates() {
+        let header: Header = serde_json::from_value(json!({
+            "msg_id": "1",
+            "session": "s",
+            "username": "u",
+            "date": "2024-05-01T12:30:00.123456",
+            "msg_type": "execute_request",
+            "version": "5.3",
+        }))
+        .unwrap();
+
+        assert_eq!(header.date.to_rfc3339(), "2024-05-01T12:30:00.123456+00:00");
+        assert_eq!(
+            serde_json::to_value(&header).unwrap()["date"],
+            "2024-05-01T12:30:00.123456Z"
+        );
+    }
+
+    #[test]
+    fn header_keeps_unknown_fields() {
+        let raw = json!({
+            "msg_id": "1",
+            "session": "s",
+            "username": "u",
+            "date": "2024-05-01T12:30:00.123456Z",
+            "msg_type": "execute_request",
+            "version": "5.5",
+            "subshell_id": "abc",
+        });
+        let header: Header = serde_json::from_value(raw.clone()).unwrap();
+        assert_eq!(serde_json::to_value(&header).unwrap(), raw);
+    }
+
+    #[test]
+    fn execute_request_defaults() {
+        let content = JuContent::parse("execute_request", json!({"code": "1"})).unwrap();
+        let JuContent::ExecuteRequest(req) = content else {
+            panic!("expected execute_request, got {content:?}");
+        };
+
+        assert!(!req.silent);
+        assert!(req.store_history);
+        assert!(req.allow_stdin);
+        assert!(req.user_expressions.is_empty());
+    }
+
+    #[test]
+    fn unknown_types_keep_raw_content() {
+        let content = JuContent::parse("custom_request", json!({"a": 1})).unwrap();
+        assert_eq!(content.msg_type(), "custom_request");
+        assert!(matches!(content, JuContent::Unknown { .. }));
+    }
+
+    #[test]
+    fn error_reply_is_flattened() {
+        let reply = Value::try_from(ExecuteReply {
+            status: ReplyStatus::Error,
+            execution_count: 3,
+            error: Some(ErrorInfo {
+                ename: "ValueError".into(),
+                evalue: "bad".into(),
+                traceback: vec![],
+            }),
+            payload: vec![],
+            user_expressions: Map::new(),
+        })
+        .unwrap();
+
+        assert_eq!(reply["status"], "error");
+        assert_eq!(reply["ename"], "ValueError");
+        assert_eq!(reply["execution_count"], 3);
+    }
+
+    #[test]
+    fn payloads_are_tagged_by_source() {
+        let payload = [
+            Payload::Page {
+                data: json!({"text/plain": "help"}),
+                start: 0,
+            },
+            Payload::SetNextInput {
+                text: "x = 1".into(),
+                replace: false,
+            },
+            Payload::EditMagic {
+                filename: "a.rs".into(),
+                line_number: 3,
+            },
+            Payload::AskExit { keepkernel: true },
+        ];
+
+        assert_eq!(
+            serde_json::to_value(&payload).unwrap(),
+            json!([
+                {"source": "page", "data": {"text/plain": "help"}, "start": 0},
+                {"source": "set_next_input", "text": "x = 1", "replace": false},
+                {"source": "edit_magic", "filename": "a.rs", "line_number": 3},
+                {"source": "ask_exit", "keepkernel": true},
+            ])
+        );
+    }
+}
@@ -0,0 +1,173 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+
+use crate::config::JuServerConfig;
+
+/// Remembers recently seen message signatures, per session, so that a
+/// captured message cannot be resent verbatim.
+///
+/// Signed messages dated outside the window are refused by
+/// [`ReplayGuard::is_timely`], so signatures only need remembering for as long
+/// as their message could still pass that check.
+pub(crate) struct ReplayGuard {
+    window: Duration,
+    skew: Duration,
+    capacity: usize,
+    sessions: HashMap<String, SeenMessages>,
+}
+
+#[derive(Default)]
+struct SeenMessages {
+    order: VecDeque<(Instant, Bytes)>,
+    keys: HashSet<Bytes>,
+}
+
+impl ReplayGuard {
+    pub(crate) fn new(config: &JuServerConfig) -> Self {
+        Self {
+            window: config.replay_window,
+            skew: config.replay_clock_skew,
+            capacity: config.replay_cache_size,
+            sessions: HashMap::new(),
+        }
+    }
+
+    /// Whether a message dated `date` is within the window of now, give or
+    /// take the allowed clock skew.
+    pub(crate) fn is_timely(&self, date: DateTime<Utc>) -> bool {
+        if self.window.is_zero() {
+            return true;
+        }
+
+        let age = (Utc::now() - date).abs().to_std().unwrap_or(Duration::MAX);
+        age <= self.window.saturating_add(self.skew)
+    }
+
+    /// Records `key` for `session`, returning `false` if it was already seen
+    /// within the window.
+    pub(crate) fn check(&mut self, session: &str, key: Bytes) -> bool {
+        if self.window.is_zero() || self.capacity == 0 {
+            return true;
+        }
+
+        let now = Instant::now();
+        self.expire(now);
+
+        let seen = self.sessions.entry(session.to_string()).or_default();
+        if seen.keys.contains(&key) {
+            return false;
+        }
+
+        // A busy client must not be locked out, so a full session forgets its
+        // oldest signature; stale dates still keep the oldest messages out.
+        if seen.order.len() >= self.capacity
+            && let Some((_, oldest)) = seen.order.pop_front()
+        {
+            seen.keys.remove(&oldest);
+        }
+
+        seen.order.push_back((now, key.clone()));
+        seen.keys.insert(key);
+        true
+    }
+
+    /// How long a signature must be kept. A message accepted now may be
+    /// dated up to window plus skew ahead, and stays timely for as long
+    /// again after that date.
+    fn retention(&self) -> Duration {
+        self.window.saturating_add(self.skew).saturating_mul(2)
+    }
+
+    fn expire(&mut self, now: Instant) {
+        let retention = self.retention();
+        for seen in self.sessions.values_mut() {
+            while let Some((at, _)) = seen.order.front() {
+                if now.duration_since(*at) <= retention {
+                    break;
+                }
+                if let Some((_, key)) = seen.order.pop_front() {
+                    seen.keys.remove(&key);
+                }
+            }
+        }
+        self.sessions.retain(|_, seen| !seen.order.is_empty());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn guard(window: Duration, capacity: usize) -> ReplayGuard {
+        ReplayGuard::new(&JuServerConfig {
+            replay_window: window,
+            replay_clock_skew: Duration::ZERO,
+            replay_cache_size: capacity,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn duplicate_is_refused() {
+        let mut g = guard(Duration::from_secs(60), 16);
+        assert!(g.check("s1", Bytes::from_static(b"sig-a")));
+        assert!(!g.check("s1", Bytes::from_static(b"sig-a")));
+        assert!(g.check("s1", Bytes::from_static(b"sig-b")));
+    }
+
+    #[test]
+    fn sessions_are_independent() {
+        let mut g = guard(Duration::from_secs(60), 16);
+        assert!(g.check("s1", Bytes::from_static(b"sig-a")));
+        assert!(g.check("s2", Bytes::from_static(b"sig-a")));
+    }
+
+    #[test]
+    fn capacity_evicts_oldest() {
+        let mut g = guard(Duration::from_secs(60), 2);
+        assert!(g.check("s", Bytes::from_static(b"1")));
+        assert!(g.check("s", Bytes::from_static(b"2")));
+        assert!(g.check("s", Bytes::from_static(b"3")));
+        assert!(!g.check("s", Bytes::from_static(b"2")));
+        assert!(!g.check("s", Bytes::from_static(b"3")));
+        assert!(g.check("s", Bytes::from_static(b"1")));
+    }
+
+    #[test]
+    fn entries_expire_after_retention() {
+        let mut g = guard(Duration::from_millis(50), 16);
+        assert!(g.check("s", Bytes::from_static(b"sig")));
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(!g.check("s", Bytes::from_static(b"sig")));
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(g.check("s", Bytes::from_static(b"sig")));
+    }
+
+    #[test]
+    fn dates_outside_window_and_skew_are_untimely() {
+        let g = ReplayGuard::new(&JuServerConfig {
+            replay_window: Duration::from_secs(60),
+            replay_clock_skew: Duration::from_secs(10),
+            ..Default::default()
+        });
+        let now = Utc::now();
+        assert!(g.is_timely(now));
+        assert!(g.is_timely(now - chrono::Duration::seconds(65)));
+        assert!(g.is_timely(now + chrono::Duration::seconds(65)));
+        assert!(!g.is_timely(now - chrono::Duration::seconds(75)));
+        assert!(!g.is_timely(now + chrono::Duration::seconds(75)));
+    }
+
+    #[test]
+    fn zero_window_disables() {
+        let mut g = guard(Duration::ZERO, 16);
+        assert!(g.check("s", Bytes::from_static(b"sig")));
+        assert!(g.check("s", Bytes::from_static(b"sig")));
+        assert!(g.is_timely(Utc::now() - chrono::Duration::days(1)));
+    }
+}
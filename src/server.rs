@@ -1,87 +1,666 @@
-use std::sync::Arc;
-
-use serde_json::json;
-use tokio::sync::Notify;
+use tokio::select;
+use tokio_util::sync::CancellationToken;
 use tracing::{debug, error, info, warn};
 
 use crate::{
-    ConnectionInfo, JuKernel, JuMessage, JuResult, server_id::JuServerId, shell_processor::JuShellProcessor, sockets::HBSocket
+    ConnectionInfo, JuKernel, JuMessage, JuResult, comm::CommManager, config::JuServerConfig, debugger::{self, Debugger}, iopub::IoPub,
+    protocol::{DebugReply, DebugRequest, InterruptReply, JuContent, ReplyStatus, ShutdownReply}, server_id::JuServerId, shell_processor::JuShellProcessor, sockets::HBSocket,
+    stdin::Stdin,
 };
 
 pub struct JuServer {
     control_sock: HBSocket<zeromq::RouterSocket>,
     jsi: JuServerId,
-    notify: Arc<Notify>,
+    /// Cancelled once the shell processor is done, which ends the control
+    /// loop, the heartbeat and the signal handlers.
+    stopped: CancellationToken,
+    debugger: Option<Debugger>,
 }
 
 impl JuServer {
-    pub async fn start<K: JuKernel>(ci: &ConnectionInfo, imp: K) -> JuResult<bool> {
-        let jsi = JuServerId::new(&ci)?;
+    /// Serves `imp` until a client asks for a shutdown. Restart requests are
+    /// handled without returning: the sockets stay bound and the kernel is
+    /// reset through [`JuKernel::restart`].
+    pub async fn start<K: JuKernel>(ci: &ConnectionInfo, imp: K) -> JuResult<()> {
+        Self::start_with_config(ci, imp, JuServerConfig::default()).await
+    }
 
-        let mut hb_socket = HBSocket::<zeromq::RepSocket>::new(&ci, ci.hb_port).await?;
-        tokio::spawn(async move {
-            hb_socket.run().await?;
-            error!("Heartbeat socket exited unexpectedly");
-            anyhow::Ok(())
-        });
+    pub async fn start_with_config<K: JuKernel>(
+        ci: &ConnectionInfo,
+        imp: K,
+        config: JuServerConfig,
+    ) -> JuResult<()> {
+        let jsi = JuServerId::new(ci, &config)?;
+
+        let mut hb_socket = HBSocket::<zeromq::RepSocket>::new(ci, ci.hb_port).await?;
+        let shell_sock = HBSocket::<zeromq::RouterSocket>::new(ci, ci.shell_port).await?;
+        let control_sock = HBSocket::<zeromq::RouterSocket>::new(ci, ci.control_port).await?;
+        let iopub_sock = HBSocket::<zeromq::PubSocket>::new(ci, ci.iopub_port).await?;
+        let stdin_sock = HBSocket::<zeromq::RouterSocket>::new(ci, ci.stdin_port).await?;
 
-        let shell_sock = HBSocket::<zeromq::RouterSocket>::new(&ci, ci.shell_port).await?;
-        let control_sock = HBSocket::<zeromq::RouterSocket>::new(&ci, ci.control_port).await?;
-        let iopub_sock = HBSocket::<zeromq::PubSocket>::new(&ci, ci.iopub_port).await?;
+        let mut bound = ci.clone();
+        bound.hb_port = hb_socket.port();
+        bound.shell_port = shell_sock.port();
+        bound.control_port = control_sock.port();
+        bound.iopub_port = iopub_sock.port();
+        bound.stdin_port = stdin_sock.port();
+        info!(
+            "Kernel listening on {}://{} (shell {}, iopub {}, stdin {}, control {}, hb {})",
+            bound.transport,
+            bound.ip,
+            bound.shell_port,
+            bound.iopub_port,
+            bound.stdin_port,
+            bound.control_port,
+            bound.hb_port
+        );
 
-        let notify = Arc::new(Notify::new());
+        if let Some(path) = &config.connection_file {
+            bound.write_connection_file(path)?;
+            info!("Wrote connection file {:?}", path);
+        }
+
+        let stopped = CancellationToken::new();
+        spawn_signal_handlers(&jsi, &stopped)?;
+
+        let hb_stopped = stopped.clone();
+        let hb = tokio::spawn(async move {
+            select! {
+                res = hb_socket.run() => match res {
+                    Ok(()) => error!("Heartbeat socket exited unexpectedly"),
+                    Err(e) => error!("Heartbeat socket failed: {:?}", e),
+                },
+                _ = hb_stopped.cancelled() => {}
+            }
+            hb_socket.close().await;
+        });
 
-        let shell_processor = JuShellProcessor::new(shell_sock, iopub_sock, jsi.clone(), imp, notify.clone()).await?;
+        let iopub = IoPub::spawn(iopub_sock, jsi.clone());
+        let stdin = Stdin::new(stdin_sock, jsi.clone());
+        let shell_processor = JuShellProcessor::new(
+            shell_sock,
+            iopub.clone(),
+            CommManager::default(),
+            stdin.clone(),
+            jsi.clone(),
+            imp,
+        );
 
         let srv = Self {
             control_sock,
-            jsi,
-            notify,
+            jsi: jsi.clone(),
+            stopped: stopped.clone(),
+            debugger: shell_processor.debugger(),
         };
 
-        let jh = tokio::spawn(srv.run());
+        let control = tokio::spawn(srv.run());
 
-        shell_processor.run().await?;
+        let res = shell_processor.run().await;
 
-        jh.await?
-    }
+        stopped.cancel();
+        let control_res = control.await?;
+        hb.await?;
 
-    async fn run(mut self) -> JuResult<bool> {
+        stdin.close().await;
+        iopub.close().await;
+        info!("All sockets closed");
 
+        control_res?;
+        res
+    }
+
+    /// Answers control requests until the shell processor is done, so that
+    /// interrupts still work while a shutdown waits for the running
+    /// execution.
+    async fn run(mut self) -> JuResult<()> {
         loop {
-            let msg = self.control_sock.recv().await?;
-            debug!("Control socket received Jupyter message: {:?}", msg);
-
-            match msg.header["msg_type"].as_str() {
-                Some("shutdown_request") => {
-                    let want_restart = match msg.content["restart"].as_bool() {
-                        Some(b) => b,
-                        None => false,
-                    };
+            let msg = select! {
+                _ = self.stopped.cancelled() => {
+                    debug!("Shell processor done, exiting control loop");
+                    self.control_sock.close().await;
+                    return Ok(());
+                }
+                res = self.control_sock.recv(&self.jsi) => res?,
+            };
+            debug!(
+                "Control socket received Jupyter message: {:?}",
+                msg.log_view(self.jsi.config.redact_message_logs)
+            );
+
+            match msg.typed_content() {
+                Ok(JuContent::ShutdownRequest(req)) => {
+                    let want_restart = req.restart;
+
+                    let reply = self.jsi.new_reply_message(&msg).try_with_content(ShutdownReply {
+                        status: ReplyStatus::Ok,
+                        restart: want_restart,
+                    })?;
+
+                    self.send_control(reply).await?;
 
-                    let reply = self.jsi.new_reply_message(&msg).with_content(json!({
-                        "status": "ok",
-                        "restart": want_restart,
-                    }));
+                    info!("Shutdown request received (restart: {})", want_restart);
+                    self.jsi.shutdown.request(want_restart, Some(msg.header.clone()));
+                }
+                Ok(JuContent::InterruptRequest(_)) => {
+                    if self.jsi.interrupter.interrupt() {
+                        info!("Interrupting the running execution");
+                    } else {
+                        debug!("Interrupt request with nothing running");
+                    }
 
+                    let reply = self.jsi.new_reply_message(&msg).try_with_content(InterruptReply {
+                        status: ReplyStatus::Ok,
+                    })?;
                     self.send_control(reply).await?;
+                }
+                Ok(JuContent::DebugRequest(DebugRequest(request))) => {
+                    let response = match &self.debugger {
+                        Some(debugger) => debugger.handle(&msg.header, request),
+                        None => debugger::response(
+                            debugger::next_seq(&self.jsi.debug_seq),
+                            &request,
+                            Err("The kernel has no debugger".to_string()),
+                        ),
+                    };
 
-                    info!("Shutdown request received, exiting server loop");
-                    self.notify.notify_one();
-                    return Ok(want_restart);
+                    let reply = self.jsi.new_reply_message(&msg).try_with_content(DebugReply(response))?;
+                    self.send_control(reply).await?;
                 }
-                Some(msg_type) => {
-                    warn!("Unsupported control message type: {:?}", msg_type);
+                Ok(other) => {
+                    warn!("Unsupported control message type: {:?}", other.msg_type());
                 }
-                None => {
-                    error!("Control message missing msg_type field");
+                Err(e) => {
+                    error!("Malformed {} control message: {:?}", msg.msg_type(), e);
                 }
             }
         }
     }
 
     pub(crate) async fn send_control(&mut self, msg: JuMessage) -> JuResult<()> {
-        debug!("Sending control message: {:?}", msg);
+        debug!("Sending control message: {:?}", msg.log_view(self.jsi.config.redact_message_logs));
         self.control_sock.send(msg, &self.jsi.digester).await
     }
 }
+
+/// Turns SIGINT into an interrupt and SIGTERM into a shutdown, as configured.
+/// The handlers stop with `stopped`.
+#[cfg(unix)]
+fn spawn_signal_handlers(jsi: &JuServerId, stopped: &CancellationToken) -> JuResult<()> {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    if jsi.config.interrupt_on_sigint {
+        let mut sigint = signal(SignalKind::interrupt())?;
+        let interrupter = jsi.interrupter.clone();
+        let stopped = stopped.clone();
+        tokio::spawn(async move {
+            loop {
+                select! {
+                    _ = stopped.cancelled() => break,
+                    Some(()) = sigint.recv() => {
+                        if interrupter.interrupt() {
+                            info!("SIGINT received, interrupting the running execution");
+                        } else {
+                            debug!("SIGINT received with nothing running");
+                        }
+                    }
+                }
+            }
+        });
+    }
+
+    if jsi.config.shutdown_on_sigterm {
+        let mut sigterm = signal(SignalKind::terminate())?;
+        let shutdown = jsi.shutdown.clone();
+        let stopped = stopped.clone();
+        tokio::spawn(async move {
+            select! {
+                _ = stopped.cancelled() => {}
+                Some(()) = sigterm.recv() => {
+                    info!("SIGTERM received, shutting down");
+                    shutdown.request(false, None);
+                }
+            }
+        });
+    }
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn spawn_signal_handlers(jsi: &JuServerId, stopped: &CancellationToken) -> JuResult<()> {
+    if jsi.config.interrupt_on_sigint {
+        let interrupter = jsi.interrupter.clone();
+        let stopped = stopped.clone();
+        tokio::spawn(async move {
+            loop {
+                select! {
+                    _ = stopped.cancelled() => break,
+                    Ok(()) = tokio::signal::ctrl_c() => {
+                        if interrupter.interrupt() {
+                            info!("Ctrl-C received, interrupting the running execution");
+                        }
+                    }
+                }
+            }
+        });
+    }
+
+    if jsi.config.shutdown_on_sigterm {
+        warn!("SIGTERM handling is only available on unix");
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use serde_json::{Value, json};
+    use zeromq::{SocketRecv, SocketSend};
+
+    use super::*;
+    use crate::{
+        JuExecContext, JuKernelInfo,
+        message::{EvalResult, EvalValue},
+        testing::{Client, states},
+    };
+
+    /// Sleeps for as many milliseconds as the code says, ignoring interrupts,
+    /// and returns the code. `fail` fails instead, and `wait` waits until
+    /// interrupted; user expressions evaluate to themselves.
+    struct Sleeper {
+        events: Arc<Mutex<Vec<&'static str>>>,
+    }
+
+    impl JuKernel for Sleeper {
+        fn kernel_info(&self) -> JuKernelInfo {
+            JuKernelInfo {
+                name: "sleeper".to_string(),
+                version: "0".to_string(),
+                mimetype: "text/plain".to_string(),
+                file_extension: ".txt".to_string(),
+                banner: String::new(),
+                help_links: Vec::new(),
+            }
+        }
+
+        async fn eval_code(&mut self, code: String, ctx: JuExecContext) -> EvalResult {
+            if code == "wait" {
+                ctx.interrupted().await;
+                self.events.lock().unwrap().push("interrupted");
+                return EvalResult::Success {
+                    results: Vec::new(),
+                    payload: Vec::new(),
+                };
+            }
+            if code == "fail" {
+                return EvalResult::Error {
+                    ename: "ValueError".to_string(),
+                    evalue: "failed".to_string(),
+                    traceback: Vec::new(),
+                    payload: Vec::new(),
+                };
+            }
+            tokio::time::sleep(Duration::from_millis(code.parse().unwrap())).await;
+            EvalResult::Success {
+                results: vec![text(&code)],
+                payload: Vec::new(),
+            }
+        }
+
+        async fn user_expression(&mut self, expr: String) -> EvalResult {
+            EvalResult::Success {
+                results: vec![text(&expr)],
+                payload: Vec::new(),
+            }
+        }
+
+        async fn shutdown(&mut self, restart: bool) {
+            let event = if restart { "shutdown(restart)" } else { "shutdown" };
+            self.events.lock().unwrap().push(event);
+        }
+
+        async fn restart(&mut self) {
+            self.events.lock().unwrap().push("restart");
+            tokio::time::sleep(Duration::from_millis(200)).await;
+        }
+    }
+
+    fn text(s: &str) -> EvalValue {
+        EvalValue {
+            data: json!({ "text/plain": s }),
+            metadata: json!({}),
+            buffers: Vec::new(),
+        }
+    }
+
+    fn execute(code: &str) -> Value {
+        json!({ "code": code, "silent": false })
+    }
+
+    /// Runs a [`Sleeper`] server alongside `client`, which is handed a
+    /// connected [`Client`] and must get the server to shut down. Returns the
+    /// kernel's events.
+    async fn run_with_client<F, Fut>(ci: &ConnectionInfo, config: JuServerConfig, client: F) -> Vec<&'static str>
+    where
+        F: FnOnce(Client) -> Fut,
+        Fut: Future<Output = ()>,
+    {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let kernel = Sleeper {
+            events: events.clone(),
+        };
+        let client = async { client(Client::connect(ci).await).await };
+
+        let (res, ()) = tokio::time::timeout(Duration::from_secs(10), async {
+            tokio::join!(JuServer::start_with_config(ci, kernel, config), client)
+        })
+        .await
+        .unwrap();
+        res.unwrap();
+
+        events.lock().unwrap().clone()
+    }
+
+    fn assert_ports_released(ci: &ConnectionInfo) {
+        for port in [ci.shell_port(), ci.iopub_port(), ci.stdin_port(), ci.control_port(), ci.hb_port()] {
+            std::net::TcpListener::bind(("127.0.0.1", port)).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn shutdown_interrupts_a_long_execution_after_the_grace_period() {
+        let ci = ConnectionInfo::builder().build().unwrap();
+        let config = JuServerConfig {
+            shutdown_timeout: Duration::from_millis(100),
+            interrupt_timeout: Duration::from_millis(100),
+            ..Default::default()
+        };
+
+        let events = run_with_client(&ci, config, |mut client| async move {
+            client.shell.send(client.request("execute_request", execute("60000"))).await.unwrap();
+            client.shell.send(client.request("execute_request", execute("0"))).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(100)).await;
+
+            let shutdown = client.request("shutdown_request", json!({ "restart": false }));
+            client.control.send(shutdown).await.unwrap();
+            let reply = client.control.recv().await.unwrap();
+            let reply = client.parse(reply);
+            assert_eq!(reply.msg_type(), "shutdown_reply");
+            assert_eq!(reply.content["status"], "ok");
+
+            let reply = client.shell.recv().await.unwrap();
+            let reply = client.parse(reply);
+            assert_eq!(reply.msg_type(), "execute_reply");
+            assert_eq!(reply.content["status"], "error");
+            assert_eq!(reply.content["ename"], "KeyboardInterrupt");
+
+            // The queued request is dropped rather than run, and the
+            // shutdown ends with a final status.
+            let statuses = client.statuses_until_quiet().await;
+            assert_eq!(states(&statuses), ["busy", "idle", "busy", "shutdown_reply", "idle"]);
+            let parent = statuses[4].parent_header.as_ref().unwrap();
+            assert_eq!(parent.msg_type, "shutdown_request");
+            assert_eq!(statuses[3].content, json!({ "status": "ok", "restart": false }));
+        })
+        .await;
+        assert_eq!(events, ["shutdown"]);
+        assert_ports_released(&ci);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn sigterm_shuts_down_with_a_final_status() {
+        let ci = ConnectionInfo::builder().build().unwrap();
+        let config = JuServerConfig {
+            shutdown_on_sigterm: true,
+            ..Default::default()
+        };
+
+        let events = run_with_client(&ci, config, |mut client| async move {
+            client.shell_request("execute_request", execute("0")).await;
+
+            // SAFETY: raise only sends a signal, which the server handles.
+            unsafe { libc::raise(libc::SIGTERM) };
+            let statuses = client.statuses_until_quiet().await;
+            assert_eq!(states(&statuses), ["busy", "idle", "busy", "shutdown_reply", "idle"]);
+            assert_eq!(statuses[4].parent_header, None);
+        })
+        .await;
+        assert_eq!(events, ["shutdown"]);
+    }
+
+    #[tokio::test]
+    async fn restart_keeps_the_sockets_and_starts_a_new_session() {
+        let ci = ConnectionInfo::builder().build().unwrap();
+        let config = JuServerConfig {
+            shutdown_timeout: Duration::from_secs(5),
+            ..Default::default()
+        };
+
+        let events = run_with_client(&ci, config, |mut client| async move {
+            client.shell.send(client.request("execute_request", execute("300"))).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(100)).await;
+
+            let shutdown = client.request("shutdown_request", json!({ "restart": true }));
+            client.control.send(shutdown).await.unwrap();
+            let reply = client.control.recv().await.unwrap();
+            let reply = client.parse(reply);
+            assert_eq!(reply.content["restart"], true);
+
+            // The execution finishes within the grace period.
+            let reply = client.shell.recv().await.unwrap();
+            let before = client.parse(reply);
+            assert_eq!(before.content["status"], "ok");
+            assert_eq!(before.content["execution_count"], 1);
+
+            client.shell.send(client.request("execute_request", execute("0"))).await.unwrap();
+            let reply = client.shell.recv().await.unwrap();
+            let after = client.parse(reply);
+            assert_eq!(after.content["status"], "ok");
+            assert_eq!(after.content["execution_count"], 1);
+            assert_ne!(after.header.session, before.header.session);
+
+            let shutdown = client.request("shutdown_request", json!({ "restart": false }));
+            client.control.send(shutdown).await.unwrap();
+            let statuses = client.statuses_until_quiet().await;
+            assert_eq!(
+                states(&statuses),
+                [
+                    "busy", "idle", "busy", "shutdown_reply", "idle", "starting", "idle", "busy", "idle", "busy",
+                    "shutdown_reply", "idle",
+                ]
+            );
+        })
+        .await;
+        assert_eq!(events, ["shutdown(restart)", "restart", "shutdown"]);
+        assert_ports_released(&ci);
+    }
+
+    #[tokio::test]
+    async fn shutdown_requested_while_restarting_is_carried_out() {
+        let ci = ConnectionInfo::builder().build().unwrap();
+
+        let events = run_with_client(&ci, JuServerConfig::default(), |mut client| async move {
+            let restart = client.request("shutdown_request", json!({ "restart": true }));
+            client.control.send(restart).await.unwrap();
+            client.control.recv().await.unwrap();
+
+            // The kernel's restart hook is still running.
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            let shutdown = client.request("shutdown_request", json!({ "restart": false }));
+            client.control.send(shutdown).await.unwrap();
+            let reply = client.control.recv().await.unwrap();
+            let reply = client.parse(reply);
+            assert_eq!(reply.content["restart"], false);
+        })
+        .await;
+        assert_eq!(events, ["shutdown(restart)", "restart", "shutdown"]);
+    }
+
+    #[tokio::test]
+    async fn silent_requests_publish_no_input_or_result() {
+        let ci = ConnectionInfo::builder().build().unwrap();
+
+        run_with_client(&ci, JuServerConfig::default(), |mut client| async move {
+            let silent = json!({ "code": "5", "silent": true });
+            let reply = client.shell_request("execute_request", silent).await;
+            assert_eq!(reply.content["status"], "ok");
+            assert_eq!(reply.content["execution_count"], 0);
+
+            let reply = client.shell_request("execute_request", execute("6")).await;
+            assert_eq!(reply.content["execution_count"], 1);
+
+            let outputs = client
+                .iopub_until(&["execute_input", "execute_result"], "execute_result")
+                .await;
+            let types: Vec<_> = outputs.iter().map(|m| m.msg_type()).collect();
+            assert_eq!(types, ["execute_input", "execute_result"]);
+            assert_eq!(outputs[0].content["code"], "6");
+            assert_eq!(outputs[1].content["data"]["text/plain"], "6");
+            client.shut_down().await;
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn store_history_false_leaves_the_execution_count_alone() {
+        let ci = ConnectionInfo::builder().build().unwrap();
+
+        run_with_client(&ci, JuServerConfig::default(), |mut client| async move {
+            let reply = client.shell_request("execute_request", execute("0")).await;
+            assert_eq!(reply.content["execution_count"], 1);
+
+            let unstored = json!({ "code": "0", "store_history": false });
+            let reply = client.shell_request("execute_request", unstored).await;
+            assert_eq!(reply.content["status"], "ok");
+            assert_eq!(reply.content["execution_count"], 1);
+
+            let reply = client.shell_request("execute_request", execute("0")).await;
+            assert_eq!(reply.content["execution_count"], 2);
+            client.shut_down().await;
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn user_expressions_come_back_in_the_reply() {
+        let ci = ConnectionInfo::builder().build().unwrap();
+
+        run_with_client(&ci, JuServerConfig::default(), |mut client| async move {
+            let request = json!({ "code": "0", "user_expressions": { "answer": "42" } });
+            let reply = client.shell_request("execute_request", request).await;
+            assert_eq!(
+                reply.content["user_expressions"],
+                json!({
+                    "answer": { "status": "ok", "data": { "text/plain": "42" }, "metadata": {} },
+                })
+            );
+            client.shut_down().await;
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn requests_queued_behind_an_error_are_aborted() {
+        let ci = ConnectionInfo::builder().build().unwrap();
+
+        run_with_client(&ci, JuServerConfig::default(), |mut client| async move {
+            client.shell.send(client.request("execute_request", execute("fail"))).await.unwrap();
+            client.shell.send(client.request("execute_request", execute("0"))).await.unwrap();
+
+            let reply = client.shell.recv().await.unwrap();
+            let failed = client.parse(reply);
+            assert_eq!(failed.content["status"], "error");
+            assert_eq!(failed.content["ename"], "ValueError");
+            let reply = client.shell.recv().await.unwrap();
+            let aborted = client.parse(reply);
+            assert_eq!(aborted.content["status"], "aborted");
+
+            // Silent failures abort nothing.
+            let silent = json!({ "code": "fail", "silent": true });
+            client.shell.send(client.request("execute_request", silent)).await.unwrap();
+            client.shell.send(client.request("execute_request", execute("0"))).await.unwrap();
+            let reply = client.shell.recv().await.unwrap();
+            assert_eq!(client.parse(reply).content["status"], "error");
+            let reply = client.shell.recv().await.unwrap();
+            assert_eq!(client.parse(reply).content["status"], "ok");
+            client.shut_down().await;
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn shutdown_cuts_the_abort_window_short() {
+        let ci = ConnectionInfo::builder().build().unwrap();
+        let config = JuServerConfig {
+            stop_on_error_timeout: Duration::from_secs(60),
+            ..Default::default()
+        };
+
+        let events = run_with_client(&ci, config, |mut client| async move {
+            let reply = client.shell_request("execute_request", execute("fail")).await;
+            assert_eq!(reply.content["status"], "error");
+            client.shut_down().await;
+        })
+        .await;
+        assert_eq!(events, ["shutdown"]);
+    }
+
+    /// Interrupts the execution started by `code` and checks that it ends with
+    /// a `KeyboardInterrupt`.
+    async fn interrupt_execution(ci: &ConnectionInfo, config: JuServerConfig, code: &str) -> Vec<&'static str> {
+        run_with_client(ci, config, |mut client| async move {
+            client.shell.send(client.request("execute_request", execute(code))).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(100)).await;
+
+            let interrupt = client.request("interrupt_request", json!({}));
+            client.control.send(interrupt).await.unwrap();
+            let reply = client.control.recv().await.unwrap();
+            let reply = client.parse(reply);
+            assert_eq!(reply.msg_type(), "interrupt_reply");
+            assert_eq!(reply.content["status"], "ok");
+
+            let reply = client.shell.recv().await.unwrap();
+            let reply = client.parse(reply);
+            assert_eq!(reply.msg_type(), "execute_reply");
+            assert_eq!(reply.content["status"], "error");
+            assert_eq!(reply.content["ename"], "KeyboardInterrupt");
+
+            // The kernel is still usable once the abort window is over.
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            let reply = client.shell_request("execute_request", execute("0")).await;
+            assert_eq!(reply.content["status"], "ok");
+            client.shut_down().await;
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn interrupt_stops_a_kernel_that_yields() {
+        let ci = ConnectionInfo::builder().build().unwrap();
+        let config = JuServerConfig {
+            interrupt_timeout: Duration::from_secs(60),
+            ..Default::default()
+        };
+
+        let events = interrupt_execution(&ci, config, "wait").await;
+        assert_eq!(events, ["interrupted", "shutdown"]);
+    }
+
+    #[tokio::test]
+    async fn interrupt_drops_a_kernel_that_does_not_yield() {
+        let ci = ConnectionInfo::builder().build().unwrap();
+        let config = JuServerConfig {
+            interrupt_timeout: Duration::from_millis(100),
+            ..Default::default()
+        };
+
+        let events = interrupt_execution(&ci, config, "60000").await;
+        assert_eq!(events, ["shutdown"]);
+    }
+}
@@ -1,42 +1,75 @@
-use serde_json::{Value, json};
+use std::sync::{Arc, Mutex, atomic::AtomicU64};
+
+use serde_json::json;
 use uuid::Uuid;
 
-use crate::{ConnectionInfo, JuMessage, JuResult, digester::Digester};
+use crate::{
+    ConnectionInfo, JuMessage, JuResult, config::JuServerConfig, digester::Digester,
+    history::JuHistory, interrupt::Interrupter, protocol::Header, replay::ReplayGuard, shutdown::Shutdown, version::ProtocolNegotiator,
+};
 
 #[derive(Clone)]
 pub(crate) struct JuServerId {
-    pub session_id: Uuid,
+    session_id: Arc<Mutex<Uuid>>,
     pub digester: Digester,
+    pub replay: Arc<Mutex<ReplayGuard>>,
+    pub config: Arc<JuServerConfig>,
+    pub protocol: Arc<ProtocolNegotiator>,
+    pub history: Arc<Mutex<JuHistory>>,
+    pub interrupter: Interrupter,
+    pub shutdown: Shutdown,
+    /// Numbers the DAP responses and events the kernel sends.
+    pub debug_seq: Arc<AtomicU64>,
 }
 
 impl JuServerId {
-    pub(crate) fn new(ci: &ConnectionInfo) -> JuResult<Self> {
-        let digester = Digester::new(&ci)?;
+    pub(crate) fn new(ci: &ConnectionInfo, config: &JuServerConfig) -> JuResult<Self> {
+        let digester = Digester::new(ci)?;
 
         Ok(Self {
-            session_id: Uuid::new_v4(),
+            session_id: Arc::new(Mutex::new(Uuid::new_v4())),
             digester,
+            replay: Arc::new(Mutex::new(ReplayGuard::new(config))),
+            config: Arc::new(config.clone()),
+            protocol: Arc::new(ProtocolNegotiator::new(config.protocol_version)),
+            history: Arc::new(Mutex::new(JuHistory::open(config)?)),
+            interrupter: Interrupter::default(),
+            shutdown: Shutdown::default(),
+            debug_seq: Default::default(),
         })
     }
 
-    pub(crate) fn new_header<T: Into<String>>(&self, msg_type: T) -> Value {
-        json!({
-            "msg_id": Uuid::new_v4().to_string(),
-            "username": "kernel",
-            "session": self.session_id.to_string(),
-            "msg_type": msg_type.into(),
-            "version": "5.3",
-            "date": chrono::Utc::now().to_rfc3339(),
-        })
+    pub(crate) fn session_id(&self) -> Uuid {
+        *self.session_id.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Switches every clone to a fresh session id, as after a restart.
+    pub(crate) fn new_session(&self) -> Uuid {
+        let session = Uuid::new_v4();
+        *self.session_id.lock().unwrap_or_else(|e| e.into_inner()) = session;
+        session
+    }
+
+    pub(crate) fn new_header<T: Into<String>>(&self, msg_type: T) -> Header {
+        Header {
+            msg_id: Uuid::new_v4().to_string(),
+            session: self.session_id().to_string(),
+            username: "kernel".to_string(),
+            date: chrono::Utc::now(),
+            msg_type: msg_type.into(),
+            version: self.config.protocol_version.to_string(),
+            extra: Default::default(),
+        }
     }
 
     pub(crate) fn new_message<T: Into<String>>(&self, msg_type: T) -> JuMessage {
         JuMessage {
             zmq_ids: Vec::new(),
             header: self.new_header(msg_type),
-            parent_header: json!({}),
+            parent_header: None,
             metadata: json!({}),
             content: json!({}),
+            buffers: Vec::new(),
         }
     }
 
@@ -46,27 +79,37 @@ impl JuServerId {
         JuMessage {
             zmq_ids: Vec::new(),
             header,
-            parent_header: msg.header.clone(),
+            parent_header: Some(msg.header.clone()),
             metadata: json!({}),
             content: json!({}),
+            buffers: Vec::new(),
         }
     }
 
     pub(crate) fn new_reply_message(&self, msg: &JuMessage) -> JuMessage {
-        let msg_type = msg
-            .header
-            .get("msg_type")
-            .and_then(|v| v.as_str())
-            .unwrap_or("")
-            .replace("_request", "_reply");
+        let msg_type = msg.header.msg_type.replace("_request", "_reply");
         let header = self.new_header(msg_type);
 
         JuMessage {
             zmq_ids: msg.zmq_ids.clone(),
             header,
-            parent_header: msg.header.clone(),
+            parent_header: Some(msg.header.clone()),
             metadata: json!({}),
             content: json!({}),
+            buffers: Vec::new(),
         }
     }
 }
+
+#[cfg(test)]
+impl JuServerId {
+    /// For tests that bind no sockets.
+    pub(crate) fn for_test() -> Self {
+        Self::for_test_connection(&ConnectionInfo::builder().probe_ports(false).build().unwrap())
+    }
+
+    /// For tests against `ci`, with the default configuration.
+    pub(crate) fn for_test_connection(ci: &ConnectionInfo) -> Self {
+        Self::new(ci, &JuServerConfig::default()).unwrap()
+    }
+}
@@ -1,219 +1,651 @@
-use std::sync::Arc;
+use std::{
+    collections::{BTreeMap, VecDeque},
+    time::Duration,
+};
 
-use serde_json::{Value, json};
-use tokio::{select, sync::Notify};
-use tracing::{debug, error};
+use serde_json::{Map, Value, json};
+use tokio::{
+    select,
+    time::{Instant, sleep, timeout_at},
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, warn};
 
-use crate::{JuError, JuKernel, JuMessage, JuResult, server_id::JuServerId, sockets::HBSocket};
+use crate::{
+    JuCompletion, JuError, JuIsComplete, JuKernel, JuMessage, JuResult,
+    comm::CommManager,
+    context::JuExecContext,
+    cursor,
+    debugger::Debugger,
+    handle::JuServerHandle,
+    history::JuHistoryQuery,
+    iopub::IoPub,
+    message::EvalResult,
+    protocol::{
+        CommInfoReply, CompleteReply, ErrorContent, ErrorInfo, ExecuteInput, ExecuteRequest, ExecuteReply, ExecuteResult, ExecutionState,
+        HelpLink, HistAccessType, HistoryReply, InspectReply, IsCompleteReply, IsCompleteStatus, JuContent, KernelInfoReply, LanguageInfo,
+        Payload, ReplyStatus, ShutdownReply, Status,
+    },
+    server_id::JuServerId,
+    shutdown::{PendingShutdown, Shutdown},
+    sockets::HBSocket,
+    stdin::{ActiveRequest, Stdin},
+};
 
 pub(crate) struct JuShellProcessor<K: JuKernel> {
     shell_sock: HBSocket<zeromq::RouterSocket>,
-    iopub_sock: HBSocket<zeromq::PubSocket>,
+    iopub: IoPub,
+    comms: CommManager,
+    stdin: Stdin,
     jsi: JuServerId,
     execution_count: u32,
+    /// Requests received while aborting after an error, still to be answered.
+    queued: VecDeque<JuMessage>,
+    /// Whether queued execute requests are answered `aborted`.
+    aborting: bool,
     imp: K,
-    notify: Arc<Notify>,
+    debugger: Option<Debugger>,
 }
 
 impl<K: JuKernel> JuShellProcessor<K> {
-    pub(crate) async fn new(
+    pub(crate) fn new(
         shell_sock: HBSocket<zeromq::RouterSocket>,
-        iopub_sock: HBSocket<zeromq::PubSocket>,
+        iopub: IoPub,
+        comms: CommManager,
+        stdin: Stdin,
         jsi: JuServerId,
         imp: K,
-        notify: Arc<Notify>,
-    ) -> JuResult<Self> {
-        let mut res = Self {
+    ) -> Self {
+        let mut imp = imp;
+        imp.attach(JuServerHandle::new(
+            jsi.clone(),
+            iopub.clone(),
+            comms.clone(),
+            Some(stdin.clone()),
+        ));
+        let debugger = imp
+            .debugger()
+            .map(|debugger| Debugger::new(debugger, iopub.clone(), jsi.debug_seq.clone()));
+
+        let res = Self {
             shell_sock,
-            iopub_sock,
+            iopub,
+            comms,
+            stdin,
             jsi,
             execution_count: 0,
+            queued: VecDeque::new(),
+            aborting: false,
             imp,
-            notify,
+            debugger,
         };
 
-        let starting_msg = res
-            .jsi
-            .new_message("status")
-            .with_content(serde_json::json!({
-                "execution_state": "starting",
-            }));
+        res.send_status(ExecutionState::Starting);
+        res
+    }
+
+    /// The kernel's debugger, which the control channel drives.
+    pub(crate) fn debugger(&self) -> Option<Debugger> {
+        self.debugger.clone()
+    }
 
-        res.send_pub(starting_msg).await?;
-        Ok(res)
+    /// Publishes a status that belongs to no request.
+    fn send_status(&self, execution_state: ExecutionState) {
+        match self.jsi.new_message("status").try_with_content(Status { execution_state }) {
+            Ok(msg) => self.send_pub(msg),
+            Err(e) => error!("Could not publish {:?} status: {}", execution_state, e),
+        }
     }
 
-    pub(crate) async fn send_pub(&mut self, msg: JuMessage) -> JuResult<()> {
-        debug!("Sending iopub message: {:?}", msg);
-        self.iopub_sock.send(msg, &self.jsi.digester).await
+    pub(crate) fn send_pub(&self, msg: JuMessage) {
+        self.iopub.send(msg);
     }
 
     pub(crate) async fn send_shell(&mut self, msg: JuMessage) -> JuResult<()> {
-        debug!("Sending shell message: {:?}", msg);
+        debug!("Sending shell message: {:?}", msg.log_view(self.jsi.config.redact_message_logs));
         self.shell_sock.send(msg, &self.jsi.digester).await
     }
 
+    /// Answers shell requests until a shutdown is requested, then lets the
+    /// kernel clean up and closes the shell socket. Restarts are carried out
+    /// in place.
     pub(crate) async fn run(mut self) -> JuResult<()> {
-        let idle_msg = self
-            .jsi
-            .new_message("status")
-            .with_content(serde_json::json!({
-                "execution_state": "idle",
-            }));
-
-        self.send_pub(idle_msg).await?;
+        self.send_status(ExecutionState::Idle);
 
         loop {
-            select! {
-                _ = self.notify.notified() => {
-                    debug!("Shutdown notification received, exiting shell processor loop");
-                    return Ok(());
+            if let Some(pending) = self.jsi.shutdown.take() {
+                if !self.queued.is_empty() {
+                    warn!("Dropping {} queued requests at shutdown", self.queued.len());
+                    self.queued.clear();
                 }
-                res = self.shell_sock.recv() => {
-                    let msg = res?;
-                    debug!("Received shell message: {:?}", msg);
-
-                    debug!("Shell socket received Jupyter message: {:?}", msg);
-
-                    let busy_msg =
-                        self.jsi
-                            .new_derived_message(&msg, "status")
-                            .with_content(serde_json::json!({
-                                "execution_state": "busy",
-                            }));
-
-                    self.send_pub(busy_msg).await?;
-
-                    match self.process_shell_msg(msg).await {
-                        Ok(()) => {}
-                        Err(e) => {
-                            error!("Error processing shell message: {:?}", e);
-                        }
+
+                self.shutdown(pending.clone()).await;
+                if !pending.restart {
+                    break;
+                }
+                self.restart().await;
+                continue;
+            }
+
+            // Requests collected while aborting go first; aborting ends with
+            // them.
+            let msg = match self.queued.pop_front() {
+                Some(msg) => msg,
+                None => {
+                    self.aborting = false;
+                    select! {
+                        _ = self.jsi.shutdown.requested() => continue,
+                        res = self.shell_sock.recv(&self.jsi) => res?,
                     }
+                }
+            };
+            debug!(
+                "Shell socket received Jupyter message: {:?}",
+                msg.log_view(self.jsi.config.redact_message_logs)
+            );
 
-                    let idle_msg = self
-                        .jsi
-                        .new_message("status")
-                        .with_content(serde_json::json!({
-                            "execution_state": "idle",
-                        }));
+            self.iopub.set_parent(Some(msg.header.clone()));
 
-                    self.send_pub(idle_msg).await?;
+            let busy_msg = self
+                .jsi
+                .new_derived_message(&msg, "status")
+                .try_with_content(Status {
+                    execution_state: ExecutionState::Busy,
+                })?;
+            let idle_msg = self
+                .jsi
+                .new_derived_message(&msg, "status")
+                .try_with_content(Status {
+                    execution_state: ExecutionState::Idle,
+                })?;
+
+            self.send_pub(busy_msg);
+
+            match self.process_shell_msg(msg).await {
+                Ok(()) => {}
+                Err(e) => {
+                    error!("Error processing shell message: {:?}", e);
                 }
             }
+
+            self.send_pub(idle_msg);
         }
+
+        debug!("Shutdown requested, exiting shell processor loop");
+        self.shell_sock.close().await;
+        Ok(())
+    }
+
+    /// Runs the kernel's shutdown hook between busy and idle statuses and
+    /// broadcasts the `shutdown_reply` on IOPub, as ipykernel does. All of it
+    /// is parented to the `shutdown_request`, if there was one.
+    async fn shutdown(&mut self, pending: PendingShutdown) {
+        let restart = pending.restart;
+        self.iopub.set_parent(pending.parent);
+        let status = |execution_state| Status { execution_state };
+
+        self.iopub.publish("status", status(ExecutionState::Busy), Vec::new());
+        self.imp.shutdown(restart).await;
+        let reply = ShutdownReply {
+            status: ReplyStatus::Ok,
+            restart,
+        };
+        self.iopub.publish("shutdown_reply", reply, Vec::new());
+        self.iopub.publish("status", status(ExecutionState::Idle), Vec::new());
+    }
+
+    /// Starts over with a new session on the same sockets, so that clients
+    /// stay connected through a restart.
+    async fn restart(&mut self) {
+        let session = self.jsi.new_session();
+        info!("Restarting kernel in place, new session {}", session);
+
+        self.iopub.set_parent(None);
+        self.send_status(ExecutionState::Starting);
+
+        self.execution_count = 0;
+        self.aborting = false;
+        self.comms.reset();
+        self.jsi.history.lock().unwrap_or_else(|e| e.into_inner()).new_session();
+        self.imp.restart().await;
+
+        self.send_status(ExecutionState::Idle);
     }
 
     async fn process_shell_msg(&mut self, msg: JuMessage) -> JuResult<()> {
-        if msg.header["msg_type"] == "kernel_info_request" {
-            let info = self.imp.kernel_info();
-
-            let reply = self.jsi.new_reply_message(&msg).with_content(json!({
-                "protocol_version": "5.3",
-                "implementation": "juker",
-                "implementation_version": "0.1.0",
-                "language_info": {
-                    "name": info.name,
-                    "version": info.version,
-                    "mimetype": info.mimetype,
-                    "file_extension": info.file_extension
-                },
-                "banner": info.banner,
-                "help_links": info.help_links.iter().map(|link| json!({
-                    "text": link.text,
-                    "url": link.url,
-                })).collect::<Vec<_>>(),
-            }));
-            self.send_shell(reply).await?;
-        } else if msg.header["msg_type"] == "is_complete_request" {
-            let reply = self.jsi.new_reply_message(&msg).with_content(json!({
-                "status": "unknown",
-            }));
-            self.send_shell(reply).await?;
-        } else if msg.header["msg_type"] == "execute_request" {
-            self.execution_count += 1;
+        match msg.typed_content()? {
+            JuContent::KernelInfoRequest(_) => {
+                let info = self.imp.kernel_info();
+                let version = self.jsi.config.protocol_version;
 
-            let code = match &msg.content["code"] {
-                Value::String(s) => s.clone(),
-                _ => {
-                    return Err(JuError::NoCode(
-                        "execute_request message missing 'code' field".into(),
-                    ));
-                }
-            };
+                let reply = self.jsi.new_reply_message(&msg).try_with_content(KernelInfoReply {
+                    status: ReplyStatus::Ok,
+                    protocol_version: version.to_string(),
+                    implementation: "juker".to_string(),
+                    implementation_version: "0.1.0".to_string(),
+                    language_info: LanguageInfo {
+                        name: info.name,
+                        version: info.version,
+                        mimetype: info.mimetype,
+                        file_extension: info.file_extension,
+                    },
+                    banner: info.banner,
+                    help_links: info
+                        .help_links
+                        .into_iter()
+                        .map(|link| HelpLink {
+                            text: link.text,
+                            url: link.url,
+                        })
+                        .collect(),
+                    debugger: version.has_debugger().then_some(self.debugger.is_some()),
+                    supported_features: version.has_supported_features().then(|| {
+                        self.debugger.iter().map(|_| "debugger".to_string()).collect()
+                    }),
+                })?;
+                self.send_shell(reply).await?;
+            }
+            JuContent::CompleteRequest(req) => {
+                let code_points = self.jsi.protocol.negotiated_for(&msg.header).counts_code_points();
+                let cursor_pos = cursor::to_byte_offset(&req.code, req.cursor_pos, code_points);
+                let completion = self.imp.complete(req.code.clone(), cursor_pos).await;
+
+                let reply = self
+                    .jsi
+                    .new_reply_message(&msg)
+                    .try_with_content(complete_reply(&req.code, completion, code_points))?;
+                self.send_shell(reply).await?;
+            }
+            JuContent::InspectRequest(req) => {
+                let code_points = self.jsi.protocol.negotiated_for(&msg.header).counts_code_points();
+                let cursor_pos = cursor::to_byte_offset(&req.code, req.cursor_pos, code_points);
+                let inspection = self.imp.inspect(req.code, cursor_pos, req.detail_level).await;
+
+                let reply = match inspection {
+                    Some(found) => InspectReply {
+                        status: ReplyStatus::Ok,
+                        found: true,
+                        data: found.data,
+                        metadata: found.metadata,
+                    },
+                    None => InspectReply {
+                        status: ReplyStatus::Ok,
+                        found: false,
+                        data: json!({}),
+                        metadata: json!({}),
+                    },
+                };
+                let reply = self.jsi.new_reply_message(&msg).try_with_content(reply)?;
+                self.send_shell(reply).await?;
+            }
+            JuContent::HistoryRequest(req) => {
+                let query = match req.hist_access_type {
+                    HistAccessType::Tail => JuHistoryQuery::Tail {
+                        n: req.n.unwrap_or(10),
+                    },
+                    HistAccessType::Range => JuHistoryQuery::Range {
+                        session: req.session.unwrap_or(0),
+                        start: req.start.unwrap_or(0).clamp(0, u32::MAX.into()) as u32,
+                        stop: req.stop.map(|stop| stop.clamp(0, u32::MAX.into()) as u32),
+                    },
+                    HistAccessType::Search => JuHistoryQuery::Search {
+                        pattern: req.pattern.unwrap_or_else(|| "*".to_string()),
+                        n: req.n,
+                        unique: req.unique,
+                    },
+                };
+
+                let history = self
+                    .jsi
+                    .history
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .query(&query)
+                    .iter()
+                    .map(|entry| entry.to_reply_value(req.output))
+                    .collect();
+
+                let reply = self.jsi.new_reply_message(&msg).try_with_content(HistoryReply {
+                    status: ReplyStatus::Ok,
+                    history,
+                })?;
+                self.send_shell(reply).await?;
+            }
+            JuContent::CommInfoRequest(req) => {
+                let reply = self.jsi.new_reply_message(&msg).try_with_content(CommInfoReply {
+                    status: ReplyStatus::Ok,
+                    comms: self.comms.info(req.target_name.as_deref()),
+                })?;
+                self.send_shell(reply).await?;
+            }
+            JuContent::CommOpen(req) => self.comms.handle_open(&self.iopub, req, msg.buffers),
+            JuContent::CommMsg(req) => self.comms.handle_msg(req, msg.buffers),
+            JuContent::CommClose(req) => self.comms.handle_close(req),
+            JuContent::IsCompleteRequest(req) => {
+                let (status, indent) = match self.imp.is_complete(req.code).await {
+                    JuIsComplete::Complete => (IsCompleteStatus::Complete, None),
+                    JuIsComplete::Incomplete { indent } => (IsCompleteStatus::Incomplete, Some(indent)),
+                    JuIsComplete::Invalid => (IsCompleteStatus::Invalid, None),
+                    JuIsComplete::Unknown => (IsCompleteStatus::Unknown, None),
+                };
+                let reply = self
+                    .jsi
+                    .new_reply_message(&msg)
+                    .try_with_content(IsCompleteReply { status, indent })?;
+                self.send_shell(reply).await?;
+            }
+            JuContent::ExecuteRequest(req) => self.execute(&msg, req).await?,
+            other => return Err(JuError::UnsupportedMessageType(other.msg_type().to_string())),
+        }
+
+        Ok(())
+    }
+    async fn execute(&mut self, msg: &JuMessage, req: ExecuteRequest) -> JuResult<()> {
+        if self.aborting {
+            debug!("Aborting execute request {} after an earlier error", msg.header.msg_id);
+            let reply = self.jsi.new_reply_message(msg).try_with_content(ExecuteReply {
+                status: ReplyStatus::Aborted,
+                execution_count: self.execution_count,
+                error: None,
+                payload: Vec::new(),
+                user_expressions: Default::default(),
+            })?;
+            return self.send_shell(reply).await;
+        }
 
+        // Silent requests never touch the history and broadcast nothing.
+        let silent = req.silent;
+        let store_history = req.store_history && !silent;
+        if store_history {
+            self.execution_count += 1;
+        }
+
+        if !silent {
             let code_msg = self
                 .jsi
-                .new_derived_message(&msg, "execute_input")
-                .with_content(json!({
-                    "code": code,
-                    "execution_count": self.execution_count,
-                }));
-            self.send_pub(code_msg).await?;
-
-            // TODO: handle interrupts, cancellations, etc.
-            let eval_result = self.imp.eval_code(code).await;
-
-            match eval_result {
-                crate::message::EvalResult::Success { results } => {
-                    debug!("Code executed successfully");
-
-                    let reply = self.jsi.new_reply_message(&msg).with_content(json!({
-                        "status": "ok",
-                        "execution_count": self.execution_count,
-                    }));
-
-                    self.send_shell(reply).await?;
-
-                    for ev in results {
-                        let output_msg = self
-                            .jsi
-                            .new_derived_message(&msg, "execute_result")
-                            .with_content(json!({
-                                "data": ev.data,
-                                "metadata": ev.metadata,
-                                "execution_count": self.execution_count,
-                            }));
-                        self.send_pub(output_msg).await?;
-                    }
+                .new_derived_message(msg, "execute_input")
+                .try_with_content(ExecuteInput {
+                    code: req.code.clone(),
+                    execution_count: self.execution_count,
+                })?;
+            self.send_pub(code_msg);
+        }
+
+        let cancel = self.jsi.interrupter.begin();
+        self.stdin.set_active(Some(ActiveRequest {
+            zmq_ids: msg.zmq_ids.clone(),
+            header: msg.header.clone(),
+            allow_stdin: req.allow_stdin,
+            cancel: cancel.clone(),
+        }));
+        let ctx = JuExecContext::new(
+            self.iopub.clone(),
+            Some(self.stdin.clone()),
+            msg.header.clone(),
+            self.execution_count,
+            msg.metadata.clone(),
+            cancel.clone(),
+        );
+
+        let config = &self.jsi.config;
+        let eval_result = select! {
+            res = self.imp.eval_code(req.code.clone(), ctx) => res,
+            _ = eval_deadline(&cancel, &self.jsi.shutdown, config.shutdown_timeout, config.interrupt_timeout) => {
+                warn!(
+                    "Execution did not stop within {:?} of the interrupt, dropping it",
+                    config.interrupt_timeout
+                );
+                EvalResult::interrupted()
+            }
+        };
+        self.stdin.set_active(None);
+        self.jsi.interrupter.end();
+
+        // An interrupted cell fails even if the kernel finished it anyway,
+        // unless the kernel reported its own error.
+        let eval_result = match eval_result {
+            EvalResult::Success { .. } if cancel.is_cancelled() => EvalResult::interrupted(),
+            other => other,
+        };
+
+        if store_history {
+            let output = match &eval_result {
+                EvalResult::Success { results, .. } => results
+                    .first()
+                    .and_then(|ev| ev.data["text/plain"].as_str())
+                    .map(str::to_string),
+                EvalResult::Error { .. } => None,
+            };
+            self.jsi
+                .history
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .record(self.execution_count, req.code, output);
+        }
+
+        match eval_result {
+            EvalResult::Success { results, payload } => {
+                debug!("Code executed successfully");
+
+                let user_expressions = self.user_expressions(req.user_expressions).await;
+                let reply = self.jsi.new_reply_message(msg).try_with_content(ExecuteReply {
+                    status: ReplyStatus::Ok,
+                    execution_count: self.execution_count,
+                    error: None,
+                    payload: payload_values(payload)?,
+                    user_expressions,
+                })?;
+
+                self.send_shell(reply).await?;
+
+                if silent {
+                    return Ok(());
                 }
-                crate::message::EvalResult::Error {
+                for ev in results {
+                    let output_msg = self
+                        .jsi
+                        .new_derived_message(msg, "execute_result")
+                        .try_with_content(ExecuteResult {
+                            execution_count: self.execution_count,
+                            data: ev.data,
+                            metadata: ev.metadata,
+                        })?
+                        .with_buffers(ev.buffers);
+                    self.send_pub(output_msg);
+                }
+            }
+            EvalResult::Error {
+                ename,
+                evalue,
+                traceback,
+                payload,
+            } => {
+                error!("Code execution encountered an error");
+
+                let error = ErrorInfo {
                     ename,
                     evalue,
                     traceback,
-                } => {
-                    error!("Code execution encountered an error");
+                };
 
-                    let reply = self.jsi.new_reply_message(&msg).with_content(json!({
-                        "status": "error",
-                        "ename": ename,
-                        "evalue": evalue,
-                        "traceback": traceback,
-                        "execution_count": self.execution_count,
-                    }));
+                let reply = self.jsi.new_reply_message(msg).try_with_content(ExecuteReply {
+                    status: ReplyStatus::Error,
+                    execution_count: self.execution_count,
+                    error: Some(error.clone()),
+                    payload: payload_values(payload)?,
+                    user_expressions: Default::default(),
+                })?;
 
-                    self.send_shell(reply).await?;
+                self.send_shell(reply).await?;
 
+                if !silent {
                     let err_msg = self
                         .jsi
-                        .new_derived_message(&msg, "error")
-                        .with_content(json!({
-                            "ename": ename,
-                            "evalue": evalue,
-                            "traceback": traceback,
-                        }));
-                    debug!("Sending iopub error message: {:?}", err_msg);
-                    self.send_pub(err_msg).await?;
+                        .new_derived_message(msg, "error")
+                        .try_with_content(ErrorContent(error))?;
+                    self.send_pub(err_msg);
+                }
+
+                if req.stop_on_error && !silent {
+                    self.abort_queued().await?;
                 }
             }
-        } else {
-            // TODO: handle other message types
+        }
+
+        Ok(())
+    }
 
-            return Err(JuError::UnsupportedMessageType(
-                msg.header["msg_type"].to_string(),
-            ));
+    /// Evaluates `user_expressions` through the kernel, in the reply format:
+    /// a MIME bundle with `status: ok`, or the error.
+    async fn user_expressions(&mut self, expressions: BTreeMap<String, String>) -> Map<String, Value> {
+        let mut values = Map::new();
+        for (name, expr) in expressions {
+            let value = match self.imp.user_expression(expr).await {
+                EvalResult::Success { results, .. } => {
+                    let (data, metadata) = results
+                        .into_iter()
+                        .next()
+                        .map_or_else(|| (json!({}), json!({})), |ev| (ev.data, ev.metadata));
+                    json!({ "status": "ok", "data": data, "metadata": metadata })
+                }
+                EvalResult::Error {
+                    ename,
+                    evalue,
+                    traceback,
+                    ..
+                } => json!({
+                    "status": "error",
+                    "ename": ename,
+                    "evalue": evalue,
+                    "traceback": traceback,
+                }),
+            };
+            values.insert(name, value);
         }
+        values
+    }
 
+    /// Collects the requests that arrive within `stop_on_error_timeout`, as
+    /// ipykernel does after a failed cell. Execute requests among them are
+    /// answered `aborted` when their turn comes; anything else runs as usual.
+    /// A shutdown request cuts the wait short.
+    async fn abort_queued(&mut self) -> JuResult<()> {
+        let deadline = Instant::now() + self.jsi.config.stop_on_error_timeout;
+        // A deadline already passed still picks up requests that are queued.
+        loop {
+            select! {
+                _ = self.jsi.shutdown.requested() => break,
+                res = timeout_at(deadline, self.shell_sock.recv(&self.jsi)) => match res {
+                    Ok(res) => self.queued.push_back(res?),
+                    Err(_) => break,
+                },
+            }
+        }
+        self.aborting = true;
         Ok(())
     }
 }
+
+/// Completes `interrupt_timeout` after the running execution is interrupted,
+/// either directly or because a shutdown's `shutdown_timeout` ran out.
+async fn eval_deadline(
+    cancel: &CancellationToken,
+    shutdown: &Shutdown,
+    shutdown_timeout: Duration,
+    interrupt_timeout: Duration,
+) {
+    select! {
+        _ = cancel.cancelled() => {}
+        _ = async {
+            shutdown.requested().await;
+            sleep(shutdown_timeout).await;
+        } => {
+            info!("Execution still running {:?} after shutdown request, interrupting it", shutdown_timeout);
+            cancel.cancel();
+        }
+    }
+    sleep(interrupt_timeout).await;
+}
+
+fn payload_values(payload: Vec<Payload>) -> JuResult<Vec<Value>> {
+    Ok(payload
+        .iter()
+        .map(serde_json::to_value)
+        .collect::<Result<_, _>>()?)
+}
+
+/// Turns a kernel's completion, in byte offsets into `code`, into a reply in
+/// protocol cursor positions.
+fn complete_reply(code: &str, completion: JuCompletion, code_points: bool) -> CompleteReply {
+    let to_pos = |offset| cursor::from_byte_offset(code, offset, code_points);
+    let mut metadata = json!({});
+    if let Some(items) = completion.items {
+        metadata["_jupyter_types_experimental"] = items
+            .into_iter()
+            .map(|item| {
+                let mut entry = json!({
+                    "start": to_pos(item.start),
+                    "end": to_pos(item.end),
+                    "text": item.text,
+                });
+                if let Some(kind) = item.kind {
+                    entry["type"] = kind.into();
+                }
+                if let Some(signature) = item.signature {
+                    entry["signature"] = signature.into();
+                }
+                entry
+            })
+            .collect();
+    }
+
+    CompleteReply {
+        status: ReplyStatus::Ok,
+        matches: completion.matches,
+        cursor_start: to_pos(completion.cursor_start),
+        cursor_end: to_pos(completion.cursor_end),
+        metadata,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::JuCompletionItem;
+
+    /// Completes "dö" in "ünï = dö", which is 8 code points and 11 bytes.
+    fn completion() -> JuCompletion {
+        let item = |text: &str, kind: Option<&str>| JuCompletionItem {
+            start: 8,
+            end: 11,
+            text: text.to_string(),
+            kind: kind.map(str::to_string),
+            signature: None,
+        };
+        JuCompletion {
+            matches: vec!["döner".to_string(), "dösen".to_string()],
+            cursor_start: 8,
+            cursor_end: 11,
+            items: Some(vec![item("döner", Some("function")), item("dösen", None)]),
+        }
+    }
+
+    #[test]
+    fn completion_positions_are_sent_as_code_points() {
+        let reply = complete_reply("ünï = dö", completion(), true);
+        assert_eq!(reply.cursor_start, 6);
+        assert_eq!(reply.cursor_end, 8);
+
+        let items = &reply.metadata["_jupyter_types_experimental"];
+        assert_eq!(items[0], json!({ "start": 6, "end": 8, "text": "döner", "type": "function" }));
+        assert_eq!(items[1], json!({ "start": 6, "end": 8, "text": "dösen" }));
+    }
+
+    #[test]
+    fn completion_without_items_has_empty_metadata() {
+        let completion = JuCompletion { items: None, ..completion() };
+        let reply = complete_reply("ünï = dö", completion, true);
+        assert_eq!(reply.matches, ["döner", "dösen"]);
+        assert_eq!(reply.metadata, json!({}));
+    }
+}
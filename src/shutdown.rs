@@ -0,0 +1,105 @@
+use std::sync::Arc;
+
+use tokio::sync::watch;
+
+use crate::protocol::Header;
+
+/// A shutdown (or restart) request shared by the channels: set by the control
+/// loop or a signal, acted on by the shell processor once the running
+/// execution is over.
+#[derive(Clone)]
+pub(crate) struct Shutdown {
+    tx: Arc<watch::Sender<Option<PendingShutdown>>>,
+}
+
+/// A shutdown waiting to be carried out.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct PendingShutdown {
+    pub restart: bool,
+    /// The `shutdown_request` that asked for it; `None` for a signal.
+    pub parent: Option<Header>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self {
+            tx: Arc::new(watch::channel(None).0),
+        }
+    }
+}
+
+impl Shutdown {
+    /// Asks for a shutdown on behalf of `parent`. A plain shutdown wins over a
+    /// pending restart, never the other way round.
+    pub(crate) fn request(&self, restart: bool, parent: Option<Header>) {
+        self.tx.send_modify(|pending| {
+            if restart && pending.as_ref().is_some_and(|pending| !pending.restart) {
+                return;
+            }
+            *pending = Some(PendingShutdown { restart, parent });
+        });
+    }
+
+    /// Takes the pending request, so that one arriving while it is carried
+    /// out is seen afterwards.
+    pub(crate) fn take(&self) -> Option<PendingShutdown> {
+        self.tx.send_replace(None)
+    }
+
+    /// Completes with the `restart` flag once a shutdown is requested.
+    pub(crate) async fn requested(&self) -> bool {
+        let mut rx = self.tx.subscribe();
+        // The sender lives in `self`, so waiting cannot fail.
+        rx.wait_for(Option::is_some)
+            .await
+            .map(|pending| pending.as_ref().is_some_and(|pending| pending.restart))
+            .unwrap_or(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn wakes_waiters() {
+        let shutdown = Shutdown::default();
+        assert_eq!(shutdown.take(), None);
+
+        let waiter = tokio::spawn({
+            let shutdown = shutdown.clone();
+            async move { shutdown.requested().await }
+        });
+        tokio::task::yield_now().await;
+
+        shutdown.request(true, None);
+        assert!(waiter.await.unwrap());
+        assert_eq!(shutdown.take().map(|pending| pending.restart), Some(true));
+        assert_eq!(shutdown.take(), None);
+    }
+
+    fn header(msg_id: &str) -> Header {
+        serde_json::from_value(serde_json::json!({
+            "msg_id": msg_id,
+            "session": "s",
+            "msg_type": "shutdown_request",
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn plain_shutdown_wins_over_restart() {
+        let shutdown = Shutdown::default();
+        shutdown.request(true, Some(header("restart")));
+        shutdown.request(false, Some(header("plain")));
+        let pending = shutdown.take().unwrap();
+        assert!(!pending.restart);
+        assert_eq!(pending.parent.unwrap().msg_id, "plain");
+
+        shutdown.request(false, Some(header("plain")));
+        shutdown.request(true, Some(header("restart")));
+        let pending = shutdown.take().unwrap();
+        assert!(!pending.restart);
+        assert_eq!(pending.parent.unwrap().msg_id, "plain");
+    }
+}
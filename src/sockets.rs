@@ -1,39 +1,176 @@
 use tracing::info;
 use tracing::trace;
-use zeromq::{ Socket, SocketRecv, SocketSend };
+use tracing::warn;
+use zeromq::{ Endpoint, Socket, SocketRecv, SocketSend };
 
-use crate::{ ConnectionInfo, JuMessage, JuResult, digester::Digester };
+use std::path::{Path, PathBuf};
+
+use bytes::Bytes;
+use zeromq::ZmqMessage;
+
+use crate::{ ConnectionInfo, DELIMITER, JuError, JuMessage, JuResult, digester::Digester, server_id::JuServerId };
 
 pub(crate) struct HBSocket<S> {
     sock: S,
     port: u16,
+    rejected: u64,
+    ipc_path: Option<PathBuf>,
 }
 
 impl<S: Socket + SocketRecv> HBSocket<S> {
-    pub(crate) async fn recv(&mut self) -> JuResult<JuMessage> {
-        let msg = self.sock.recv().await?.try_into()?;
-        Ok(msg)
+    /// Receives the next authentic message, dropping any whose signature does
+    /// not match, any signed one dated outside the replay window and any that
+    /// was already received within it.
+    pub(crate) async fn recv(&mut self, jsi: &JuServerId) -> JuResult<JuMessage> {
+        loop {
+            let zmsg = self.sock.recv().await?;
+            let sig = signature_frame(&zmsg);
+
+            let msg = match JuMessage::from_zmq_message(zmsg, &jsi.digester, &jsi.config) {
+                Ok(msg) => msg,
+                Err(JuError::InvalidSignature(sig)) => {
+                    self.rejected += 1;
+                    warn!(
+                        "{} socket rejected message with invalid signature {:?} ({} rejected so far)",
+                        self.port, sig, self.rejected
+                    );
+                    continue;
+                }
+                Err(e @ (JuError::MessageTooLarge(_) | JuError::MalformedMessage(_) | JuError::JsonError(_))) => {
+                    self.rejected += 1;
+                    warn!("{} socket dropped bad message: {} ({} rejected so far)", self.port, e, self.rejected);
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+
+            let session = msg.header.session.as_str();
+            let mut replay = jsi.replay.lock().unwrap_or_else(|e| e.into_inner());
+
+            if jsi.digester.is_signed() && !replay.is_timely(msg.header.date) {
+                self.rejected += 1;
+                warn!(
+                    "{} socket dropped stale message {:?} dated {} from session {:?} ({} rejected so far)",
+                    self.port, msg.header.msg_id, msg.header.date, session, self.rejected
+                );
+                continue;
+            }
+
+            // Unsigned sessions have empty signatures, so fall back to msg_id.
+            let key = match sig {
+                Some(sig) if !sig.is_empty() => sig,
+                _ => Bytes::from(msg.header.msg_id.clone()),
+            };
+            if !replay.check(session, key) {
+                self.rejected += 1;
+                warn!(
+                    "{} socket dropped replayed message {:?} from session {:?} ({} rejected so far)",
+                    self.port, msg.header.msg_id, session, self.rejected
+                );
+                continue;
+            }
+
+            drop(replay);
+            jsi.protocol.observe(&msg.header);
+            trace!("{} socket received {:?}", self.port, msg.log_view(jsi.config.redact_message_logs));
+            return Ok(msg);
+        }
     }
 }
 
+fn signature_frame(zmsg: &ZmqMessage) -> Option<Bytes> {
+    zmsg.iter()
+        .skip_while(|frame| frame.as_ref() != DELIMITER)
+        .nth(1)
+        .cloned()
+}
+
 impl<S: Socket + SocketSend> HBSocket<S> {
     pub(crate) async fn send(&mut self, msg: JuMessage, digester: &Digester) -> JuResult<()> {
-        let zmsg = msg.to_zmq_message(digester);
+        let zmsg = msg.to_zmq_message(digester)?;
         self.sock.send(zmsg).await?;
         Ok(())
     }
 }
 
 impl<S: Socket> HBSocket<S> {
+    /// Binds a new socket. A `port` of 0 lets the OS choose; see
+    /// [`HBSocket::port`] for the one actually bound.
     pub(crate) async fn new(ci: &ConnectionInfo, port: u16) -> JuResult<Self> {
         let mut sock = S::new();
-        let ep = sock.bind(&format!("{}://{}:{}", ci.transport, ci.ip, port)).await?;
-        info!("Created ZeroMQ REP socket: {:?}", ep);
+        let endpoint = endpoint(ci, port)?;
+        let ep = sock.bind(&endpoint).await?;
+        info!("Created ZeroMQ socket: {}", ep);
+
+        let (port, ipc_path) = match ep {
+            Endpoint::Tcp(_, bound) => (bound, None),
+            Endpoint::Ipc(path) => (port, path),
+            _ => (port, None),
+        };
 
-        Ok(Self { sock, port })
+        // Dropping the socket on error removes the file again.
+        let sock = Self { sock, port, rejected: 0, ipc_path };
+        if let Some(path) = &sock.ipc_path {
+            restrict_to_owner(path)?;
+        }
+        Ok(sock)
+    }
+
+    pub(crate) fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Unbinds the socket, waiting until its endpoints are released. Peers
+    /// stay connected until the socket is dropped.
+    pub(crate) async fn close(&mut self) {
+        for e in self.sock.unbind_all().await {
+            warn!("{} socket failed to unbind: {}", self.port, e);
+        }
+    }
+}
+
+impl<S> Drop for HBSocket<S> {
+    /// Removes the IPC socket file. zeromq also does this, but from a
+    /// background task that may not get to run before the process exits.
+    fn drop(&mut self) {
+        if let Some(path) = &self.ipc_path
+            && let Err(e) = std::fs::remove_file(path)
+            && e.kind() != std::io::ErrorKind::NotFound
+        {
+            warn!("Could not remove IPC socket {:?}: {}", path, e);
+        }
     }
 }
 
+/// Formats the endpoint for one of the kernel's sockets.
+///
+/// For `ipc`, Jupyter uses `ip` as a path prefix and the port as a numeric
+/// suffix, e.g. `ipc:///tmp/kernel-ipc-1`.
+pub(crate) fn endpoint(ci: &ConnectionInfo, port: u16) -> JuResult<String> {
+    match ci.transport.as_str() {
+        "tcp" => Ok(format!("tcp://{}:{}", ci.ip, port)),
+        "ipc" if port == 0 => Err(JuError::UnsupportedTransport(
+            "ipc sockets need explicit, non-zero ports".into(),
+        )),
+        "ipc" => Ok(format!("ipc://{}-{}", ci.ip, port)),
+        other => Err(JuError::UnsupportedTransport(other.to_string())),
+    }
+}
+
+/// Makes an IPC socket file readable and writable by its owner only.
+#[cfg(unix)]
+fn restrict_to_owner(path: &Path) -> JuResult<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn restrict_to_owner(_path: &Path) -> JuResult<()> {
+    Ok(())
+}
+
 impl<S: Socket + SocketRecv + SocketSend> HBSocket<S> {
     pub(crate) async fn run(&mut self) -> JuResult<()> {
         loop {
@@ -43,3 +180,131 @@ impl<S: Socket + SocketRecv + SocketSend> HBSocket<S> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use serde_json::json;
+    use zeromq::{ DealerSocket, RouterSocket };
+
+    use crate::config::JuServerConfig;
+
+    use super::*;
+
+    fn free_port() -> u16 {
+        std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+    }
+
+    fn message(msg_id: &str) -> JuMessage {
+        JuMessage {
+            zmq_ids: Vec::new(),
+            header: serde_json::from_value(
+                json!({"msg_id": msg_id, "session": "client", "msg_type": "kernel_info_request"}),
+            ).unwrap(),
+            parent_header: None,
+            metadata: json!({}),
+            content: json!({}),
+            buffers: Vec::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn replayed_frame_set_is_refused() {
+        let port = free_port();
+        let ci: ConnectionInfo = serde_json::from_value(json!({
+            "kernel_name": "test",
+            "ip": "127.0.0.1",
+            "control_port": 0,
+            "shell_port": port,
+            "stdin_port": 0,
+            "hb_port": 0,
+            "iopub_port": 0,
+            "key": "secret",
+            "transport": "tcp",
+            "signature_scheme": "hmac-sha256",
+        })).unwrap();
+        let jsi = JuServerId::for_test_connection(&ci);
+
+        let mut server = HBSocket::<RouterSocket>::new(&ci, port).await.unwrap();
+        let mut client = DealerSocket::new();
+        client.connect(&format!("tcp://127.0.0.1:{port}")).await.unwrap();
+
+        let first = message("1").to_zmq_message(&jsi.digester).unwrap();
+        client.send(first.clone()).await.unwrap();
+        client.send(first).await.unwrap();
+        client.send(message("2").to_zmq_message(&jsi.digester).unwrap()).await.unwrap();
+
+        let msg = server.recv(&jsi).await.unwrap();
+        assert_eq!(msg.header.msg_id, "1");
+
+        let msg = server.recv(&jsi).await.unwrap();
+        assert_eq!(msg.header.msg_id, "2");
+        assert_eq!(server.rejected, 1);
+    }
+
+    #[tokio::test]
+    async fn frame_set_replayed_after_the_window_is_refused() {
+        let port = free_port();
+        let ci = ConnectionInfo::builder().probe_ports(false).shell_port(port).build().unwrap();
+        let config = JuServerConfig {
+            replay_window: Duration::from_millis(100),
+            replay_clock_skew: Duration::ZERO,
+            ..Default::default()
+        };
+        let jsi = JuServerId::new(&ci, &config).unwrap();
+
+        let mut server = HBSocket::<RouterSocket>::new(&ci, port).await.unwrap();
+        let mut client = DealerSocket::new();
+        client.connect(&format!("tcp://127.0.0.1:{port}")).await.unwrap();
+
+        let first = message("1").to_zmq_message(&jsi.digester).unwrap();
+        client.send(first.clone()).await.unwrap();
+        let msg = server.recv(&jsi).await.unwrap();
+        assert_eq!(msg.header.msg_id, "1");
+
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        client.send(first).await.unwrap();
+        client.send(message("2").to_zmq_message(&jsi.digester).unwrap()).await.unwrap();
+
+        let msg = server.recv(&jsi).await.unwrap();
+        assert_eq!(msg.header.msg_id, "2");
+        assert_eq!(server.rejected, 1);
+    }
+
+    #[tokio::test]
+    async fn ipc_sockets_use_path_endpoints_and_clean_up() {
+        let prefix = std::env::temp_dir().join(format!("juker-test-{}", uuid::Uuid::new_v4()));
+        let ci = ConnectionInfo::builder()
+            .transport("ipc")
+            .ip(prefix.to_string_lossy())
+            .build()
+            .unwrap();
+        assert_eq!(ci.shell_port(), 1);
+        assert_eq!(endpoint(&ci, 3).unwrap(), format!("ipc://{}-3", prefix.display()));
+
+        let path = PathBuf::from(format!("{}-{}", prefix.display(), ci.shell_port()));
+        let sock = HBSocket::<RouterSocket>::new(&ci, ci.shell_port()).await.unwrap();
+        assert!(path.exists());
+
+        let mut client = DealerSocket::new();
+        client.connect(&endpoint(&ci, ci.shell_port()).unwrap()).await.unwrap();
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+
+        drop(sock);
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn reports_the_port_actually_bound() {
+        let ci = ConnectionInfo::builder().probe_ports(false).build().unwrap();
+
+        let sock = HBSocket::<RouterSocket>::new(&ci, 0).await.unwrap();
+        assert_ne!(sock.port(), 0);
+    }
+}
@@ -0,0 +1,200 @@
+use std::sync::{Arc, Mutex};
+
+use bytes::Bytes;
+use tokio::select;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, warn};
+use zeromq::RouterSocket;
+
+use crate::{
+    JuError, JuResult,
+    protocol::{Header, InputRequest, JuContent},
+    server_id::JuServerId,
+    sockets::HBSocket,
+};
+
+/// The execute request whose code is running, which input requests are
+/// addressed to.
+#[derive(Clone)]
+pub(crate) struct ActiveRequest {
+    /// Routing identity of the client, which uses the same identity on
+    /// shell and stdin.
+    pub zmq_ids: Vec<Bytes>,
+    pub header: Header,
+    pub allow_stdin: bool,
+    /// Cancelled when the execution is interrupted.
+    pub cancel: CancellationToken,
+}
+
+/// The stdin channel, on which the kernel asks the frontend for input.
+#[derive(Clone)]
+pub(crate) struct Stdin {
+    sock: Arc<tokio::sync::Mutex<HBSocket<RouterSocket>>>,
+    jsi: JuServerId,
+    active: Arc<Mutex<Option<ActiveRequest>>>,
+}
+
+impl Stdin {
+    pub(crate) fn new(sock: HBSocket<RouterSocket>, jsi: JuServerId) -> Self {
+        Self {
+            sock: Arc::new(tokio::sync::Mutex::new(sock)),
+            jsi,
+            active: Default::default(),
+        }
+    }
+
+    pub(crate) async fn close(&self) {
+        self.sock.lock().await.close().await;
+    }
+
+    pub(crate) fn set_active(&self, active: Option<ActiveRequest>) {
+        *self.active.lock().unwrap_or_else(|e| e.into_inner()) = active;
+    }
+
+    /// Sends an `input_request` to the client that sent the running execute
+    /// request and waits for its `input_reply`.
+    ///
+    /// Replies whose parent is a different input request are skipped; replies
+    /// without a parent, as `jupyter_client` sends them, are accepted.
+    pub(crate) async fn input(&self, prompt: &str, password: bool) -> JuResult<String> {
+        let active = self.active.lock().unwrap_or_else(|e| e.into_inner()).clone();
+        let Some(active) = active.filter(|active| active.allow_stdin) else {
+            return Err(JuError::StdinNotAllowed);
+        };
+
+        // One input request at a time.
+        let mut sock = self.sock.lock().await;
+
+        let mut request = self.jsi.new_message("input_request").try_with_content(InputRequest {
+            prompt: prompt.to_string(),
+            password,
+        })?;
+        request.zmq_ids = active.zmq_ids.clone();
+        request.parent_header = Some(active.header.clone());
+        let request_id = request.header.msg_id.clone();
+
+        debug!("Sending input request {}", request_id);
+        sock.send(request, &self.jsi.digester).await?;
+
+        loop {
+            let reply = select! {
+                _ = active.cancel.cancelled() => return Err(JuError::Interrupted),
+                reply = sock.recv(&self.jsi) => reply?,
+            };
+
+            let for_us = reply
+                .parent_header
+                .as_ref()
+                .is_none_or(|parent| parent.msg_id == request_id);
+
+            match reply.typed_content() {
+                Ok(JuContent::InputReply(reply)) if for_us => return Ok(reply.value),
+                Ok(other) => warn!("Ignoring unexpected {} on stdin", other.msg_type()),
+                Err(e) => warn!("Ignoring malformed {} on stdin: {:?}", reply.msg_type(), e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use zeromq::{DealerSocket, Socket, SocketOptions, SocketRecv, SocketSend, util::PeerIdentity};
+
+    use super::*;
+    use crate::{ConnectionInfo, JuMessage};
+
+    struct Setup {
+        stdin: Stdin,
+        jsi: JuServerId,
+        client: DealerSocket,
+        active: ActiveRequest,
+    }
+
+    async fn setup(allow_stdin: bool) -> Setup {
+        let ci = ConnectionInfo::builder().build().unwrap();
+        let jsi = JuServerId::for_test_connection(&ci);
+        let mut sock = HBSocket::<RouterSocket>::new(&ci, ci.stdin_port()).await.unwrap();
+
+        let mut options = SocketOptions::default();
+        options.peer_identity(PeerIdentity::try_from(b"client".to_vec()).unwrap());
+        let mut client = DealerSocket::with_options(options);
+        client
+            .connect(&format!("tcp://127.0.0.1:{}", ci.stdin_port()))
+            .await
+            .unwrap();
+
+        // The router can only address the client once it has seen it.
+        let hello = jsi.new_message("kernel_info_request");
+        client.send(hello.to_zmq_message(&jsi.digester).unwrap()).await.unwrap();
+        sock.recv(&jsi).await.unwrap();
+
+        let active = ActiveRequest {
+            zmq_ids: vec![Bytes::from_static(b"client")],
+            header: jsi.new_header("execute_request"),
+            allow_stdin,
+            cancel: CancellationToken::new(),
+        };
+        let stdin = Stdin::new(sock, jsi.clone());
+        stdin.set_active(Some(active.clone()));
+
+        Setup {
+            stdin,
+            jsi,
+            client,
+            active,
+        }
+    }
+
+    #[tokio::test]
+    async fn input_round_trip() {
+        let Setup {
+            stdin,
+            jsi,
+            mut client,
+            active,
+        } = setup(true).await;
+
+        let pending = tokio::spawn(async move { stdin.input("Name: ", false).await });
+
+        let request = client.recv().await.unwrap();
+        let request = JuMessage::from_zmq_message(request, &jsi.digester, &jsi.config).unwrap();
+        assert_eq!(request.msg_type(), "input_request");
+        assert_eq!(request.content, json!({ "prompt": "Name: ", "password": false }));
+        assert_eq!(request.parent_header.unwrap().msg_id, active.header.msg_id);
+
+        let reply = jsi
+            .new_message("input_reply")
+            .with_content(json!({ "value": "Ada" }));
+        client.send(reply.to_zmq_message(&jsi.digester).unwrap()).await.unwrap();
+
+        assert_eq!(pending.await.unwrap().unwrap(), "Ada");
+    }
+
+    #[tokio::test]
+    async fn refused_without_allow_stdin() {
+        let setup = setup(false).await;
+        let res = setup.stdin.input("?", false).await;
+        assert!(matches!(res, Err(JuError::StdinNotAllowed)));
+
+        setup.stdin.set_active(None);
+        let res = setup.stdin.input("?", false).await;
+        assert!(matches!(res, Err(JuError::StdinNotAllowed)));
+    }
+
+    #[tokio::test]
+    async fn interrupt_cancels_the_wait() {
+        let Setup {
+            stdin,
+            mut client,
+            active,
+            ..
+        } = setup(true).await;
+
+        let pending = tokio::spawn(async move { stdin.input("?", true).await });
+        client.recv().await.unwrap();
+        active.cancel.cancel();
+
+        assert!(matches!(pending.await.unwrap(), Err(JuError::Interrupted)));
+    }
+}
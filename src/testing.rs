@@ -0,0 +1,113 @@
+//! A frontend for end-to-end tests, speaking the wire protocol to a running
+//! server.
+
+use std::time::Duration;
+
+use serde_json::{Value, json};
+use zeromq::{DealerSocket, Socket, SocketRecv, SocketSend, SubSocket, ZmqMessage};
+
+use crate::{ConnectionInfo, JuMessage, config::JuServerConfig, server_id::JuServerId};
+
+/// Connected to the shell, control and IOPub sockets of a server, signing
+/// with the connection's key.
+pub(crate) struct Client {
+    jsi: JuServerId,
+    pub(crate) shell: DealerSocket,
+    pub(crate) control: DealerSocket,
+    pub(crate) iopub: SubSocket,
+}
+
+impl Client {
+    pub(crate) async fn connect(ci: &ConnectionInfo) -> Self {
+        let endpoint = |port| format!("tcp://127.0.0.1:{port}");
+
+        let mut iopub = SubSocket::new();
+        iopub.subscribe("").await.unwrap();
+        iopub.connect(&endpoint(ci.iopub_port())).await.unwrap();
+        let mut shell = DealerSocket::new();
+        shell.connect(&endpoint(ci.shell_port())).await.unwrap();
+        let mut control = DealerSocket::new();
+        control.connect(&endpoint(ci.control_port())).await.unwrap();
+        // Give the subscription time to reach the publisher.
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        Self {
+            jsi: JuServerId::new(ci, &JuServerConfig::default()).unwrap(),
+            shell,
+            control,
+            iopub,
+        }
+    }
+
+    /// A signed request, ready to send.
+    pub(crate) fn request(&self, msg_type: &str, content: Value) -> ZmqMessage {
+        let msg = self.jsi.new_message(msg_type).with_content(content);
+        msg.to_zmq_message(&self.jsi.digester).unwrap()
+    }
+
+    pub(crate) fn parse(&self, zmsg: ZmqMessage) -> JuMessage {
+        JuMessage::from_zmq_message(zmsg, &self.jsi.digester, &self.jsi.config).unwrap()
+    }
+
+    /// Sends a shell request and waits for its reply.
+    pub(crate) async fn shell_request(&mut self, msg_type: &str, content: Value) -> JuMessage {
+        self.shell.send(self.request(msg_type, content)).await.unwrap();
+        let reply = self.shell.recv().await.unwrap();
+        self.parse(reply)
+    }
+
+    /// Sends a control request and waits for its reply.
+    pub(crate) async fn control_request(&mut self, msg_type: &str, content: Value) -> JuMessage {
+        self.control.send(self.request(msg_type, content)).await.unwrap();
+        let reply = self.control.recv().await.unwrap();
+        self.parse(reply)
+    }
+
+    /// Asks the server to shut down and waits for the reply.
+    pub(crate) async fn shut_down(&mut self) {
+        self.control_request("shutdown_request", json!({ "restart": false })).await;
+    }
+
+    /// The next IOPub message.
+    pub(crate) async fn iopub_message(&mut self) -> JuMessage {
+        let msg = self.iopub.recv().await.unwrap();
+        self.parse(msg)
+    }
+
+    /// IOPub messages of the given types, up to and including the first one
+    /// of type `last`.
+    pub(crate) async fn iopub_until(&mut self, types: &[&str], last: &str) -> Vec<JuMessage> {
+        let mut msgs = Vec::new();
+        loop {
+            let msg = self.iopub_message().await;
+            let done = msg.msg_type() == last;
+            if types.contains(&msg.msg_type()) {
+                msgs.push(msg);
+            }
+            if done {
+                return msgs;
+            }
+        }
+    }
+
+    /// IOPub status and `shutdown_reply` messages from the first `busy`
+    /// until IOPub has been quiet for a while, as after a shutdown. Earlier
+    /// ones may have gone out before the subscription.
+    pub(crate) async fn statuses_until_quiet(&mut self) -> Vec<JuMessage> {
+        let mut msgs = Vec::new();
+        while let Ok(msg) = tokio::time::timeout(Duration::from_millis(500), self.iopub_message()).await {
+            let busy = msg.content["execution_state"] == "busy";
+            if matches!(msg.msg_type(), "status" | "shutdown_reply") && (busy || !msgs.is_empty()) {
+                msgs.push(msg);
+            }
+        }
+        msgs
+    }
+}
+
+/// The execution state of each status message, and the type of any other.
+pub(crate) fn states(msgs: &[JuMessage]) -> Vec<&str> {
+    msgs.iter()
+        .map(|msg| msg.content["execution_state"].as_str().unwrap_or(msg.msg_type()))
+        .collect()
+}
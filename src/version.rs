@@ -0,0 +1,255 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    str::FromStr,
+    sync::Mutex,
+};
+
+use tracing::{info, warn};
+
+use crate::{JuError, protocol::Header};
+
+/// A Jupyter messaging protocol version, e.g. `5.3`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ProtocolVersion {
+    pub major: u16,
+    pub minor: u16,
+}
+
+impl ProtocolVersion {
+    pub const V5_0: Self = Self::new(5, 0);
+    pub const V5_1: Self = Self::new(5, 1);
+    pub const V5_2: Self = Self::new(5, 2);
+    pub const V5_3: Self = Self::new(5, 3);
+    pub const V5_4: Self = Self::new(5, 4);
+    pub const V5_5: Self = Self::new(5, 5);
+
+    pub const fn new(major: u16, minor: u16) -> Self {
+        Self { major, minor }
+    }
+
+    /// `update_display_data` and `transient` display ids (5.1).
+    pub fn has_update_display_data(&self) -> bool {
+        *self >= Self::V5_1
+    }
+
+    /// Cursor positions count Unicode code points rather than UTF-16 code
+    /// units (5.2).
+    pub fn counts_code_points(&self) -> bool {
+        *self >= Self::V5_2
+    }
+
+    /// `debug_request`/`debug_event` and the `debugger` kernel info field
+    /// (5.3).
+    pub fn has_debugger(&self) -> bool {
+        *self >= Self::V5_3
+    }
+
+    /// The `supported_features` kernel info field (5.5).
+    pub fn has_supported_features(&self) -> bool {
+        *self >= Self::V5_5
+    }
+
+    /// Whether a peer speaking `other` can talk to us at all.
+    pub fn is_compatible_with(&self, other: &ProtocolVersion) -> bool {
+        self.major == other.major
+    }
+}
+
+impl Default for ProtocolVersion {
+    fn default() -> Self {
+        Self::V5_3
+    }
+}
+
+impl fmt::Display for ProtocolVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+impl FromStr for ProtocolVersion {
+    type Err = JuError;
+
+    /// Parses `major.minor`, ignoring any patch component.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bad = || JuError::MalformedMessage(format!("bad protocol version {s:?}"));
+
+        let mut parts = s.trim().split('.');
+        let major = parts.next().and_then(|p| p.parse().ok()).ok_or_else(bad)?;
+        let minor = match parts.next() {
+            Some(p) => p.parse().map_err(|_| bad())?,
+            None => 0,
+        };
+        Ok(Self::new(major, minor))
+    }
+}
+
+/// Tracks the protocol version clients announce in their headers, per
+/// client session, so that one old frontend does not change what others get.
+pub(crate) struct ProtocolNegotiator {
+    server: ProtocolVersion,
+    sessions: Mutex<HashMap<String, ProtocolVersion>>,
+    lowest: Mutex<Option<ProtocolVersion>>,
+    warned: Mutex<HashSet<String>>,
+}
+
+impl ProtocolNegotiator {
+    pub(crate) fn new(server: ProtocolVersion) -> Self {
+        Self {
+            server,
+            sessions: Mutex::new(HashMap::new()),
+            lowest: Mutex::new(None),
+            warned: Mutex::new(HashSet::new()),
+        }
+    }
+
+    pub(crate) fn server(&self) -> ProtocolVersion {
+        self.server
+    }
+
+    /// The highest version both sides understand for the client that sent
+    /// `request`: our configured version, lowered to the one its session
+    /// announced if that is older.
+    pub(crate) fn negotiated_for(&self, request: &Header) -> ProtocolVersion {
+        match self.sessions.lock().unwrap_or_else(|e| e.into_inner()).get(&request.session) {
+            Some(client) => (*client).min(self.server),
+            None => self.server,
+        }
+    }
+
+    /// The lowest version negotiated with any client so far, for messages
+    /// that answer no particular request. Never goes back up.
+    pub(crate) fn negotiated(&self) -> ProtocolVersion {
+        match *self.lowest.lock().unwrap_or_else(|e| e.into_inner()) {
+            Some(client) => client.min(self.server),
+            None => self.server,
+        }
+    }
+
+    /// Records the version in an incoming header, warning once per distinct
+    /// version that is unparseable or incompatible.
+    pub(crate) fn observe(&self, header: &Header) {
+        if header.version.is_empty() {
+            return;
+        }
+
+        let client = match header.version.parse::<ProtocolVersion>() {
+            Ok(client) => client,
+            Err(_) => {
+                if self.first_time(&header.version) {
+                    warn!(
+                        "Client session {} sent unparseable protocol version {:?}",
+                        header.session, header.version
+                    );
+                }
+                return;
+            }
+        };
+
+        if !self.server.is_compatible_with(&client) {
+            if self.first_time(&header.version) {
+                warn!(
+                    "Client session {} speaks protocol {}, which is incompatible with our {}",
+                    header.session, client, self.server
+                );
+            }
+            return;
+        }
+
+        if client != self.server && self.first_time(&header.version) {
+            info!(
+                "Client session {} speaks protocol {}, we speak {}; using {}",
+                header.session,
+                client,
+                self.server,
+                client.min(self.server)
+            );
+        }
+
+        self.sessions
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(header.session.clone(), client);
+        let mut lowest = self.lowest.lock().unwrap_or_else(|e| e.into_inner());
+        *lowest = Some(lowest.map_or(client, |lowest| lowest.min(client)));
+    }
+
+    fn first_time(&self, version: &str) -> bool {
+        self.warned
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(version.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn header(version: &str) -> Header {
+        session_header("s", version)
+    }
+
+    fn session_header(session: &str, version: &str) -> Header {
+        serde_json::from_value(json!({
+            "msg_id": "1",
+            "session": session,
+            "msg_type": "kernel_info_request",
+            "version": version,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn parses_versions() {
+        assert_eq!("5.3".parse::<ProtocolVersion>().unwrap(), ProtocolVersion::V5_3);
+        assert_eq!("5".parse::<ProtocolVersion>().unwrap(), ProtocolVersion::V5_0);
+        assert_eq!("5.4.1".parse::<ProtocolVersion>().unwrap(), ProtocolVersion::V5_4);
+        assert!("five".parse::<ProtocolVersion>().is_err());
+        assert_eq!(ProtocolVersion::V5_2.to_string(), "5.2");
+    }
+
+    #[test]
+    fn negotiates_down_to_older_clients() {
+        let n = ProtocolNegotiator::new(ProtocolVersion::V5_3);
+        assert_eq!(n.negotiated(), ProtocolVersion::V5_3);
+
+        n.observe(&header("5.1"));
+        assert_eq!(n.negotiated_for(&header("")), ProtocolVersion::V5_1);
+
+        n.observe(&header("5.5"));
+        assert_eq!(n.negotiated_for(&header("")), ProtocolVersion::V5_3);
+    }
+
+    #[test]
+    fn sessions_negotiate_independently() {
+        let n = ProtocolNegotiator::new(ProtocolVersion::V5_3);
+        n.observe(&session_header("new", "5.3"));
+        n.observe(&session_header("old", "5.0"));
+        n.observe(&session_header("new", "5.3"));
+
+        assert_eq!(n.negotiated_for(&session_header("new", "")), ProtocolVersion::V5_3);
+        assert_eq!(n.negotiated_for(&session_header("old", "")), ProtocolVersion::V5_0);
+        assert_eq!(n.negotiated_for(&session_header("unknown", "")), ProtocolVersion::V5_3);
+    }
+
+    #[test]
+    fn overall_version_is_the_lowest_seen() {
+        let n = ProtocolNegotiator::new(ProtocolVersion::V5_3);
+        n.observe(&session_header("a", "5.1"));
+        n.observe(&session_header("b", "5.2"));
+        n.observe(&session_header("a", "5.3"));
+        assert_eq!(n.negotiated(), ProtocolVersion::V5_1);
+    }
+
+    #[test]
+    fn ignores_incompatible_clients() {
+        let n = ProtocolNegotiator::new(ProtocolVersion::V5_3);
+        n.observe(&header("4.1"));
+        n.observe(&header("garbage"));
+        assert_eq!(n.negotiated(), ProtocolVersion::V5_3);
+        assert_eq!(n.negotiated_for(&header("")), ProtocolVersion::V5_3);
+    }
+}
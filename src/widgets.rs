@@ -0,0 +1,562 @@
+//! Kernel-side widget models for ipywidgets frontends.
+//!
+//! Each widget is a comm to the `jupyter.widget` target speaking the widget
+//! messaging protocol 2.1: the kernel and frontends exchange `update`
+//! messages with changed state, frontends ask for the full state with
+//! `request_state`, and `custom` messages carry events such as button clicks.
+//! Updates from one frontend are echoed back as `echo_update` so that other
+//! frontends stay in sync.
+//!
+//! Change and event callbacks run in the kernel, while the shell channel
+//! handles the frontend's message.
+//!
+//! Binary state (`buffer_paths`) is passed through in echoes but not stored;
+//! [`Widget::on_custom`] receives buffers as they arrive.
+
+use std::{
+    fmt,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use bytes::Bytes;
+use serde_json::{Map, Value, json};
+use tracing::{debug, warn};
+
+use crate::{
+    JuServerHandle,
+    comm::{JuComm, JuCommHandler},
+    message::EvalValue,
+};
+
+/// Comm target name of widget models.
+pub const TARGET_NAME: &str = "jupyter.widget";
+
+/// The widget messaging protocol version spoken, as sent in `comm_open`
+/// metadata.
+pub const PROTOCOL_VERSION: &str = "2.1.0";
+
+const CONTROLS_MODULE: (&str, &str) = ("@jupyter-widgets/controls", "2.0.0");
+const OUTPUT_MODULE: (&str, &str) = ("@jupyter-widgets/output", "1.0.0");
+
+type ChangeCallback = Box<dyn FnMut(&Widget, &str, &Value) + Send>;
+type CustomCallback = Box<dyn FnMut(&Widget, Value, Vec<Bytes>) + Send>;
+
+#[derive(Default)]
+struct Model {
+    state: Map<String, Value>,
+    on_change: Vec<ChangeCallback>,
+    on_custom: Vec<CustomCallback>,
+}
+
+/// A widget model of any type, kept in sync with its frontend views.
+///
+/// Cheap to clone; clones refer to the same model.
+#[derive(Clone)]
+pub struct Widget {
+    model: Arc<Mutex<Model>>,
+    comm: JuComm,
+}
+
+impl Widget {
+    /// Creates a model in the frontend. `state` must name the model and view
+    /// with the `_model_*` and `_view_*` keys.
+    pub fn new(server: &JuServerHandle, state: Map<String, Value>) -> Self {
+        let model = Arc::new(Mutex::new(Model {
+            state: state.clone(),
+            ..Default::default()
+        }));
+
+        let comm = server.open_comm_with_metadata(
+            TARGET_NAME,
+            json!({ "state": state, "buffer_paths": [] }),
+            json!({ "version": PROTOCOL_VERSION }),
+            Vec::new(),
+            WidgetHandler { model: model.clone() },
+        );
+
+        Self { model, comm }
+    }
+
+    pub fn model_id(&self) -> &str {
+        self.comm.id()
+    }
+
+    pub fn get(&self, key: &str) -> Option<Value> {
+        self.lock().state.get(key).cloned()
+    }
+
+    pub fn state(&self) -> Map<String, Value> {
+        self.lock().state.clone()
+    }
+
+    /// Sets one state key. See [`Widget::set_many`].
+    pub fn set(&self, key: &str, value: impl Into<Value>) {
+        let mut state = Map::new();
+        state.insert(key.to_string(), value.into());
+        self.set_many(state);
+    }
+
+    /// Updates the state, sends the keys that changed to the frontend and
+    /// runs the change callbacks for them.
+    pub fn set_many(&self, state: Map<String, Value>) {
+        let changed = self.apply(state);
+        if changed.is_empty() {
+            return;
+        }
+
+        self.comm.send(
+            json!({ "method": "update", "state": changed, "buffer_paths": [] }),
+            Vec::new(),
+        );
+        self.notify(&changed);
+    }
+
+    /// Sends a `custom` message to the frontend views.
+    pub fn send_custom(&self, content: impl Into<Value>, buffers: Vec<Bytes>) {
+        self.comm
+            .send(json!({ "method": "custom", "content": content.into() }), buffers);
+    }
+
+    /// Runs `callback` with the key and new value whenever a state key
+    /// changes, from either side.
+    pub fn on_change(&self, callback: impl FnMut(&Widget, &str, &Value) + Send + 'static) {
+        self.lock().on_change.push(Box::new(callback));
+    }
+
+    /// Runs `callback` with the content of each `custom` message from the
+    /// frontend.
+    pub fn on_custom(&self, callback: impl FnMut(&Widget, Value, Vec<Bytes>) + Send + 'static) {
+        self.lock().on_custom.push(Box::new(callback));
+    }
+
+    /// Closes the model, removing its views from the frontend.
+    pub fn close(&self) {
+        self.comm.close(json!({}));
+    }
+
+    /// The MIME bundle that displays a view of this widget.
+    pub fn mime_bundle(&self) -> Value {
+        let model_name = self.get("_model_name").unwrap_or_default();
+        json!({
+            "text/plain": format!("{}({})", model_name.as_str().unwrap_or("Widget"), self.model_id()),
+            "application/vnd.jupyter.widget-view+json": {
+                "version_major": 2,
+                "version_minor": 0,
+                "model_id": self.model_id(),
+            },
+        })
+    }
+
+    /// A result that displays this widget, for returning from
+    /// [`JuKernel::eval_code`](crate::JuKernel::eval_code).
+    pub fn to_eval_value(&self) -> EvalValue {
+        EvalValue {
+            data: self.mime_bundle(),
+            metadata: json!({}),
+            buffers: Vec::new(),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Model> {
+        self.model.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Stores `state`, returning the keys whose value changed.
+    fn apply(&self, state: Map<String, Value>) -> Map<String, Value> {
+        let mut model = self.lock();
+        let mut changed = Map::new();
+        for (key, value) in state {
+            if model.state.get(&key) != Some(&value) {
+                model.state.insert(key.clone(), value.clone());
+                changed.insert(key, value);
+            }
+        }
+        changed
+    }
+
+    // Callbacks are taken out of the model while they run, so that they can
+    // use the widget, and put back before any registered in the meantime.
+
+    fn notify(&self, changed: &Map<String, Value>) {
+        let mut callbacks = std::mem::take(&mut self.lock().on_change);
+        for (key, value) in changed {
+            for callback in &mut callbacks {
+                callback(self, key, value);
+            }
+        }
+        let mut model = self.lock();
+        callbacks.append(&mut model.on_change);
+        model.on_change = callbacks;
+    }
+
+    fn custom(&self, content: Value, buffers: Vec<Bytes>) {
+        let mut callbacks = std::mem::take(&mut self.lock().on_custom);
+        for callback in &mut callbacks {
+            callback(self, content.clone(), buffers.clone());
+        }
+        let mut model = self.lock();
+        callbacks.append(&mut model.on_custom);
+        model.on_custom = callbacks;
+    }
+}
+
+impl fmt::Debug for Widget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Widget")
+            .field("model_id", &self.model_id())
+            .field("state", &self.lock().state)
+            .finish()
+    }
+}
+
+struct WidgetHandler {
+    model: Arc<Mutex<Model>>,
+}
+
+impl JuCommHandler for WidgetHandler {
+    fn on_msg(&mut self, comm: &JuComm, data: Value, buffers: Vec<Bytes>) {
+        let widget = Widget {
+            model: self.model.clone(),
+            comm: comm.clone(),
+        };
+
+        match data["method"].as_str() {
+            Some("update") => {
+                let Some(state) = data["state"].as_object() else {
+                    warn!("Widget {} update without state", widget.model_id());
+                    return;
+                };
+
+                let changed = widget.apply(state.clone());
+                comm.send(
+                    json!({
+                        "method": "echo_update",
+                        "state": state,
+                        "buffer_paths": data["buffer_paths"].clone(),
+                    }),
+                    buffers,
+                );
+                widget.notify(&changed);
+            }
+            Some("request_state") => {
+                comm.send(
+                    json!({ "method": "update", "state": widget.state(), "buffer_paths": [] }),
+                    Vec::new(),
+                );
+            }
+            Some("custom") => widget.custom(data["content"].clone(), buffers),
+            other => debug!("Ignoring widget message with method {:?}", other),
+        }
+    }
+}
+
+fn model_state(model_name: &str, view_name: &str, module: (&str, &str)) -> Map<String, Value> {
+    let (module, version) = module;
+    let mut state = Map::new();
+    state.insert("_model_name".into(), model_name.into());
+    state.insert("_model_module".into(), module.into());
+    state.insert("_model_module_version".into(), version.into());
+    state.insert("_view_name".into(), view_name.into());
+    state.insert("_view_module".into(), module.into());
+    state.insert("_view_module_version".into(), version.into());
+    state.insert("_view_count".into(), Value::Null);
+    state.insert("_dom_classes".into(), json!([]));
+    state.insert("tabbable".into(), Value::Null);
+    state.insert("tooltip".into(), Value::Null);
+    state
+}
+
+fn control_state(model_name: &str, view_name: &str, extra: Value) -> Map<String, Value> {
+    let mut state = model_state(model_name, view_name, CONTROLS_MODULE);
+    state.insert("description".into(), "".into());
+    state.insert("description_allow_html".into(), false.into());
+    state.insert("disabled".into(), false.into());
+    if let Value::Object(extra) = extra {
+        state.extend(extra);
+    }
+    state
+}
+
+/// A slider over a range of integers.
+#[derive(Clone, Debug)]
+pub struct IntSlider(Widget);
+
+impl IntSlider {
+    pub fn new(server: &JuServerHandle, value: i64, min: i64, max: i64) -> Self {
+        let state = control_state(
+            "IntSliderModel",
+            "IntSliderView",
+            json!({
+                "value": value,
+                "min": min,
+                "max": max,
+                "step": 1,
+                "orientation": "horizontal",
+                "readout": true,
+                "readout_format": "d",
+                "continuous_update": true,
+                "behavior": "drag-tap",
+            }),
+        );
+        Self(Widget::new(server, state))
+    }
+
+    pub fn value(&self) -> i64 {
+        self.0.get("value").and_then(|v| v.as_i64()).unwrap_or_default()
+    }
+
+    pub fn set_value(&self, value: i64) {
+        self.0.set("value", value);
+    }
+
+    pub fn on_change(&self, mut callback: impl FnMut(i64) + Send + 'static) {
+        self.0.on_change(move |_, key, value| {
+            if key == "value"
+                && let Some(value) = value.as_i64()
+            {
+                callback(value);
+            }
+        });
+    }
+
+    pub fn widget(&self) -> &Widget {
+        &self.0
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Button(Widget);
+
+impl Button {
+    pub fn new(server: &JuServerHandle, description: &str) -> Self {
+        let state = control_state(
+            "ButtonModel",
+            "ButtonView",
+            json!({ "description": description, "button_style": "", "icon": "" }),
+        );
+        Self(Widget::new(server, state))
+    }
+
+    pub fn on_click(&self, mut callback: impl FnMut() + Send + 'static) {
+        self.0.on_custom(move |_, content, _| {
+            if content["event"] == "click" {
+                callback();
+            }
+        });
+    }
+
+    pub fn widget(&self) -> &Widget {
+        &self.0
+    }
+}
+
+/// A single-line text input.
+#[derive(Clone, Debug)]
+pub struct Text(Widget);
+
+impl Text {
+    pub fn new(server: &JuServerHandle, value: &str) -> Self {
+        let state = control_state(
+            "TextModel",
+            "TextView",
+            json!({ "value": value, "placeholder": "", "continuous_update": true }),
+        );
+        Self(Widget::new(server, state))
+    }
+
+    pub fn value(&self) -> String {
+        self.0
+            .get("value")
+            .and_then(|v| v.as_str().map(str::to_string))
+            .unwrap_or_default()
+    }
+
+    pub fn set_value(&self, value: &str) {
+        self.0.set("value", value);
+    }
+
+    pub fn on_change(&self, mut callback: impl FnMut(&str) + Send + 'static) {
+        self.0.on_change(move |_, key, value| {
+            if key == "value"
+                && let Some(value) = value.as_str()
+            {
+                callback(value);
+            }
+        });
+    }
+
+    /// Runs `callback` with the current value when the user presses Enter.
+    pub fn on_submit(&self, mut callback: impl FnMut(&str) + Send + 'static) {
+        self.0.on_custom(move |widget, content, _| {
+            if content["event"] == "submit" {
+                let value = widget.get("value").unwrap_or_default();
+                callback(value.as_str().unwrap_or_default());
+            }
+        });
+    }
+
+    pub fn widget(&self) -> &Widget {
+        &self.0
+    }
+}
+
+/// An output area that kernel code can write to at any time.
+#[derive(Clone, Debug)]
+pub struct Output(Widget);
+
+impl Output {
+    pub fn new(server: &JuServerHandle) -> Self {
+        let mut state = model_state("OutputModel", "OutputView", OUTPUT_MODULE);
+        state.insert("msg_id".into(), "".into());
+        state.insert("outputs".into(), json!([]));
+        Self(Widget::new(server, state))
+    }
+
+    pub fn append_stdout(&self, text: &str) {
+        self.append(json!({ "output_type": "stream", "name": "stdout", "text": text }));
+    }
+
+    pub fn append_stderr(&self, text: &str) {
+        self.append(json!({ "output_type": "stream", "name": "stderr", "text": text }));
+    }
+
+    /// Appends a MIME bundle, e.g. `{"text/html": "<b>hi</b>"}`.
+    pub fn append_display_data(&self, data: Value) {
+        self.append(json!({ "output_type": "display_data", "data": data, "metadata": {} }));
+    }
+
+    pub fn clear_output(&self) {
+        self.0.set("outputs", json!([]));
+    }
+
+    pub fn widget(&self) -> &Widget {
+        &self.0
+    }
+
+    fn append(&self, output: Value) {
+        let mut outputs = self.0.get("outputs").unwrap_or_else(|| json!([]));
+        if let Some(list) = outputs.as_array_mut() {
+            list.push(output);
+        }
+        self.0.set("outputs", outputs);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicI64, Ordering};
+
+    use tokio::sync::mpsc::UnboundedReceiver;
+
+    use super::*;
+    use crate::{
+        JuMessage, comm::CommManager, iopub::IoPub, protocol::CommMsg, server_id::JuServerId,
+    };
+
+    fn server() -> (JuServerHandle, CommManager, UnboundedReceiver<JuMessage>) {
+        let jsi = JuServerId::for_test();
+        let (iopub, rx) = IoPub::channel(jsi.clone());
+        let comms = CommManager::default();
+        (JuServerHandle::new(jsi, iopub, comms.clone(), None), comms, rx)
+    }
+
+    fn from_frontend(comms: &CommManager, widget: &Widget, data: Value) {
+        let msg = CommMsg {
+            comm_id: widget.model_id().to_string(),
+            data,
+        };
+        comms.handle_msg(msg, Vec::new());
+    }
+
+    #[test]
+    fn slider_opens_a_versioned_model() {
+        let (server, _, mut rx) = server();
+        let slider = IntSlider::new(&server, 5, 0, 10);
+
+        let open = rx.try_recv().unwrap();
+        assert_eq!(open.msg_type(), "comm_open");
+        assert_eq!(open.content["target_name"], TARGET_NAME);
+        assert_eq!(open.metadata["version"], PROTOCOL_VERSION);
+        assert_eq!(open.content["data"]["state"]["_model_name"], "IntSliderModel");
+        assert_eq!(open.content["data"]["state"]["value"], 5);
+        assert_eq!(
+            slider.widget().mime_bundle()["application/vnd.jupyter.widget-view+json"]["model_id"],
+            slider.widget().model_id()
+        );
+    }
+
+    #[test]
+    fn frontend_update_is_echoed_and_runs_callbacks() {
+        let (server, comms, mut rx) = server();
+        let slider = IntSlider::new(&server, 0, 0, 10);
+        rx.try_recv().unwrap();
+
+        let seen = Arc::new(AtomicI64::new(-1));
+        let seen_by_callback = seen.clone();
+        slider.on_change(move |value| seen_by_callback.store(value, Ordering::SeqCst));
+
+        from_frontend(
+            &comms,
+            slider.widget(),
+            json!({ "method": "update", "state": { "value": 7 }, "buffer_paths": [] }),
+        );
+
+        assert_eq!(slider.value(), 7);
+        assert_eq!(seen.load(Ordering::SeqCst), 7);
+        let echo = rx.try_recv().unwrap();
+        assert_eq!(echo.content["data"]["method"], "echo_update");
+        assert_eq!(echo.content["data"]["state"], json!({ "value": 7 }));
+    }
+
+    #[test]
+    fn kernel_side_changes_are_sent_once() {
+        let (server, _, mut rx) = server();
+        let text = Text::new(&server, "a");
+        rx.try_recv().unwrap();
+
+        text.set_value("b");
+        text.set_value("b");
+
+        let update = rx.try_recv().unwrap();
+        assert_eq!(update.content["data"], json!({ "method": "update", "state": { "value": "b" }, "buffer_paths": [] }));
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn request_state_sends_everything() {
+        let (server, comms, mut rx) = server();
+        let output = Output::new(&server);
+        output.append_stdout("hi\n");
+        rx.try_recv().unwrap();
+        rx.try_recv().unwrap();
+
+        from_frontend(&comms, output.widget(), json!({ "method": "request_state" }));
+
+        let state = &rx.try_recv().unwrap().content["data"]["state"];
+        assert_eq!(state["_model_name"], "OutputModel");
+        assert_eq!(state["outputs"][0]["text"], "hi\n");
+    }
+
+    #[test]
+    fn button_click_and_callbacks_that_update_widgets() {
+        let (server, comms, _rx) = server();
+        let button = Button::new(&server, "Go");
+        let slider = IntSlider::new(&server, 0, 0, 10);
+
+        let target = slider.clone();
+        button.on_click(move || target.set_value(target.value() + 1));
+        // A callback may change its own widget.
+        let text = Text::new(&server, "");
+        text.on_change({
+            let text = text.clone();
+            move |value| text.set_value(&value.to_uppercase())
+        });
+
+        from_frontend(&comms, button.widget(), json!({ "method": "custom", "content": { "event": "click" } }));
+        from_frontend(&comms, button.widget(), json!({ "method": "custom", "content": { "event": "click" } }));
+        from_frontend(&comms, text.widget(), json!({ "method": "update", "state": { "value": "abc" } }));
+
+        assert_eq!(slider.value(), 2);
+        assert_eq!(text.value(), "ABC");
+    }
+}